@@ -1,42 +1,229 @@
 extern crate time;
-extern crate jsonwebtoken as jwt;
 extern crate rustc_serialize;
 
-use self::jwt::{decode, Algorithm};
+use std::fmt;
 
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::{self, Json};
+
+use keys::KeySet;
+use pattern::glob_matches;
+
+/// The claims esper understands. Topic grants in `sub`, `topics`,
+/// `publish` and `subscribe` are globs, so `org123*` grants every topic
+/// starting with `org123`. The `publish` and `subscribe` lists only apply
+/// to their role while `sub` and `topics` apply to both.
 #[derive(Debug, RustcEncodable, RustcDecodable)]
 struct Claims {
     exp: i64,
-    sub: String
+    nbf: Option<i64>,
+    iat: Option<i64>,
+    sub: Option<String>,
+    topics: Option<Vec<String>>,
+    publish: Option<Vec<String>>,
+    subscribe: Option<Vec<String>>
+}
+
+/// The permission a token is checked for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Publish,
+    Subscribe
+}
+
+/// How a token is validated besides its signature
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Validation {
+    /// Role specific grants are only honoured for their role; without a
+    /// role only `sub` and `topics` grant topics
+    pub role: Option<Role>,
+    /// Seconds the `exp`, `nbf` and `iat` claims may be off to allow for
+    /// clock skew between esper and the token issuer
+    pub leeway: i64
+}
+
+/// Why a request was not authorized
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken,
+    Expired,
+    /// The named `nbf` or `iat` claim lies in the future
+    NotYetValid { claim: &'static str },
+    /// The first topic not granted by the named claim of the token
+    ForbiddenTopic { topic: String, claim: &'static str }
+}
+
+impl AuthError {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            AuthError::MissingToken => "No token given",
+            AuthError::InvalidToken => "Token is invalid",
+            AuthError::Expired => "Token has expired",
+            AuthError::NotYetValid { .. } => "Token is not valid yet",
+            AuthError::ForbiddenTopic { .. } => "Token does not grant access to the topic"
+        }
+    }
+
+    /// The claim the token was rejected on; tokens that could not be
+    /// decoded were not rejected on any claim
+    pub fn claim(&self) -> Option<&'static str> {
+        match *self {
+            AuthError::MissingToken | AuthError::InvalidToken => None,
+            AuthError::Expired => Some("exp"),
+            AuthError::NotYetValid { claim } => Some(claim),
+            AuthError::ForbiddenTopic { claim, .. } => Some(claim)
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthError::ForbiddenTopic { ref topic, claim } => {
+                write!(f, "Token does not grant access to topic {:?} in its {} claim", topic, claim)
+            }
+
+            AuthError::NotYetValid { claim } => write!(f, "Token is not valid yet according to its {} claim", claim),
+            AuthError::Expired => write!(f, "Token has expired according to its exp claim"),
+            _ => write!(f, "{}", self.description())
+        }
+    }
 }
 
 impl Claims {
-    fn is_valid(self, topic_id: &str) -> bool {
-        let timespec = time::get_time();
+    // Every grant of the token as the claim it is from and its glob
+    fn grants(&self, role: Option<Role>) -> Vec<(&'static str, &str)> {
+        let mut grants = Vec::new();
+
+        if let Some(ref sub) = self.sub {
+            grants.push(("sub", sub.as_str()));
+        }
+
+        let lists = [
+            ("topics", &self.topics, true),
+            ("publish", &self.publish, role == Some(Role::Publish)),
+            ("subscribe", &self.subscribe, role == Some(Role::Subscribe))
+        ];
 
-        if self.exp < timespec.sec {
+        for &(claim, list, applies) in lists.iter() {
+            match (applies, list) {
+                (true, &Some(ref globs)) => grants.extend(globs.iter().map(|glob| (claim, glob.as_str()))),
+                _ => ()
+            }
+        }
+
+        grants
+    }
+
+    fn allows(&self, role: Option<Role>, topic_id: &str) -> bool {
+        self.grants(role).iter().any(|&(_, glob)| glob_matches(glob, topic_id))
+    }
+
+    // The most specific claim topics are granted by, for reporting rejected topics
+    fn topic_claim(&self, role: Option<Role>) -> &'static str {
+        match (role, &self.publish, &self.subscribe, &self.topics) {
+            (Some(Role::Publish), &Some(_), _, _) => "publish",
+            (Some(Role::Subscribe), _, &Some(_), _) => "subscribe",
+            (_, _, _, &Some(_)) => "topics",
+            _ => "sub"
+        }
+    }
+
+    fn verify(self, topic_ids: &[&str], validation: &Validation) -> Result<(), AuthError> {
+        let now = time::get_time().sec;
+
+        if self.exp + validation.leeway < now {
             debug!("JWT expired");
 
-            false
-        } else if self.sub != topic_id {
-            debug!("JWT invalid subject");
+            return Err(AuthError::Expired);
+        }
+
+        // Tokens from issuers with a clock ahead of ours are accepted within the leeway
+        let early = [("nbf", self.nbf), ("iat", self.iat)];
 
-            false
-        } else {
-            debug!("JWT is valid");
+        if let Some(&(claim, _)) = early.iter().find(|&&(_, at)| at.map_or(false, |at| at > now + validation.leeway)) {
+            debug!("JWT not valid yet per its {} claim", claim);
 
-            true
+            return Err(AuthError::NotYetValid { claim: claim });
+        }
+
+        match topic_ids.iter().find(|topic_id| !self.allows(validation.role, topic_id)) {
+            Some(topic_id) => {
+                debug!("JWT does not grant topic {:?}", topic_id);
+
+                Err(AuthError::ForbiddenTopic {
+                    topic: topic_id.to_string(),
+                    claim: self.topic_claim(validation.role)
+                })
+            }
+
+            None => {
+                debug!("JWT is valid");
+
+                Ok(())
+            }
         }
     }
 }
 
 pub fn authenticate(topic_id: &str, token: &str, secret: &str) -> bool {
-    match decode::<Claims>(&token, secret.as_ref(), Algorithm::HS256) {
-        Ok(res) => res.claims.is_valid(topic_id),
+    authenticate_all(&[topic_id], token, secret)
+}
+
+/// Authenticates the token for every one of the given topics. A topic is
+/// authorized by the `sub` claim or by being matched by the `topics` claim.
+pub fn authenticate_all(topic_ids: &[&str], token: &str, secret: &str) -> bool {
+    authorize(topic_ids, token, secret).is_ok()
+}
+
+/// Like `authenticate_all` but tells why the token was rejected
+pub fn authorize(topic_ids: &[&str], token: &str, secret: &str) -> Result<(), AuthError> {
+    authorize_with(topic_ids, token, &KeySet::secret(secret), &Validation::default())
+}
+
+/// Authorizes the token for a role, honouring the grants for that role. The
+/// token may be signed with any of the keys.
+pub fn authorize_with(topic_ids: &[&str], token: &str, keys: &KeySet, validation: &Validation) -> Result<(), AuthError> {
+    match decode(token, keys) {
+        Ok(claims) => claims.verify(topic_ids, validation),
         Err(e) => {
-            debug!("JWT parse failure; err={:?}", e);
+            debug!("JWT parse failure; err={}", e);
 
-            false
+            Err(AuthError::InvalidToken)
         }
     }
 }
+
+fn decode_part(part: &str) -> Result<String, String> {
+    let bytes = try!(part.from_base64().map_err(|_| "part is not base64url".to_owned()));
+
+    String::from_utf8(bytes).map_err(|_| "part is not UTF-8".to_owned())
+}
+
+// Checks the signature with the keys for the algorithm and key ID named in
+// the header, then decodes the claims
+fn decode(token: &str, keys: &KeySet) -> Result<Claims, String> {
+    let parts: Vec<&str> = token.split('.').collect();
+
+    if parts.len() != 3 {
+        return Err("token must have three parts".to_owned());
+    }
+
+    let header = try!(Json::from_str(&try!(decode_part(parts[0]))).map_err(|e| format!("header is not JSON; {}", e)));
+    let kid = header.find("kid").and_then(|kid| kid.as_string());
+
+    let algorithm = match header.find("alg").and_then(|alg| alg.as_string()) {
+        Some(algorithm) => algorithm,
+        None => return Err("header has no alg".to_owned())
+    };
+
+    let sig = try!(parts[2].from_base64().map_err(|_| "signature is not base64url".to_owned()));
+    let signed = &token[..parts[0].len() + 1 + parts[1].len()];
+
+    if !keys.verify(algorithm, kid, signed.as_bytes(), &sig) {
+        return Err(format!("no {} key with kid {:?} verifies the signature", algorithm, kid));
+    }
+
+    json::decode(&try!(decode_part(parts[1]))).map_err(|e| format!("invalid claims; {}", e))
+}
@@ -1,193 +1,599 @@
-use {Access, Manager, Client, Topic};
+use {Access, BinaryPolicy, Manager, Client, Options, Pattern, Receipt, Topic, TopicError, TopicRules};
+
+use std::io;
+use std::mem;
+use std::str;
+use auth::AuthError;
+use message::Event;
+use router::{self, ApiError, Endpoint, RouteError};
 
 use std::io::ErrorKind::{WouldBlock as BlockingErr};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
-use hyper::{Get, Post, StatusCode, RequestUri, Decoder, Encoder, Error, Control, Next};
-use hyper::header::{ContentLength, ContentType};
+use hyper::{RequestUri, Decoder, Encoder, Error, Control, Next};
+use hyper::header::{Allow, ContentLength, ContentType};
 use hyper::mime::{Mime, TopLevel, SubLevel};
 use hyper::net::HttpStream;
 use hyper::server::{Handler, Request, Response};
+use rustc_serialize::json::{self, Json};
 use url::form_urlencoded::{Parse, parse as form_urlencoded_parse};
 
-static NOT_FOUND: &'static [u8] = b"404 Not Found";
+header! { (LastEventId, "Last-Event-ID") => [String] }
+
+// Bodies are read from the transport in chunks of this size
+const READ_SIZE: usize = 4096;
 
 enum Route {
-    NotFound,
-    Publish(Body),
+    Error(ApiError),
+    Publish(Body, Format),
+    FanOut(Body),
     Stats,
     Subscribe,
 }
 
-#[derive(Clone, Copy)]
-enum Body {
+/// The length of a publish body as announced by the request
+#[derive(Clone, Copy, Debug)]
+pub enum Body {
     Len(u64),
     Chunked
 }
 
+/// How a publish body encodes its events, see `publish_body`
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+    Text,
+    Json,
+    JsonArray,
+    NdJson
+}
+
 pub struct EventStream {
     id: Client,
     msg_buf: Vec<u8>,
-    msg_pos: usize,
     out_buf: Vec<u8>,
     route: Route,
     topic: Topic,
+    topics: Vec<Topic>,
+    pattern: Option<Pattern>,
+    last_event_id: Option<String>,
+    token: Option<String>,
     control: Control,
+    options: Arc<Options>,
     access: Arc<Access>,
-    manager: Arc<Mutex<Manager>>
+    manager: Arc<Manager>
 }
 
 impl EventStream {
-    pub fn new(ctrl: Control, acc: Arc<Access>, mgr: Arc<Mutex<Manager>>) -> EventStream {
+    pub fn new(ctrl: Control, opts: Arc<Options>, acc: Arc<Access>, mgr: Arc<Manager>) -> EventStream {
         EventStream {
             id: Client::new(),
-            msg_buf: vec![0; 4096],
-            msg_pos: 0,
+            msg_buf: Vec::new(),
             out_buf: vec![0; 0],
             topic: Topic::new(),
-            route: Route::NotFound,
+            topics: Vec::new(),
+            pattern: None,
+            last_event_id: None,
+            token: None,
+            route: Route::Error(ApiError::from_route(RouteError::NotFound)),
             control: ctrl,
+            options: opts,
             access: acc,
             manager: mgr
         }
     }
 }
 
-fn find_token(params: &mut Parse) -> Option<String> {
-    match params.find(|ref tuple| tuple.0 == "token") {
-        Some(pair) => Some(pair.1.into_owned()),
-        None => None
+// Bodies without a Content-Length are chunked and only checked while reading
+fn find_body(request: &Request<HttpStream>, max_size: usize) -> Result<Body, ApiError> {
+    match request.headers().get::<ContentLength>() {
+        Some(len) if **len > max_size as u64 => Err(ApiError::too_large(max_size)),
+        Some(len) => Ok(Body::Len(**len)),
+        None => Ok(Body::Chunked)
     }
 }
 
-impl Handler<HttpStream> for EventStream {
-    fn on_request(&mut self, request: Request<HttpStream>) -> Next {
-        info!("{} {}", request.method(), request.uri());
+// The request URI as logged, without the query as it may hold a token
+fn log_uri(uri: &RequestUri) -> String {
+    let uri = uri.to_string();
 
-        match *request.uri() {
-            RequestUri::AbsolutePath { ref path, ref query } => {
-                let token = match *query {
-                    Some(ref qs) => {
-                        let mut params = form_urlencoded_parse(qs.as_bytes());
+    match uri.find('?') {
+        Some(pos) => uri[..pos].to_owned(),
+        None => uri
+    }
+}
 
-                        find_token(&mut params)
+/// Reads what the transport has of a publish body into `buf`, in chunks of
+/// `READ_SIZE`. Returns the whole body once it is complete and `None` when
+/// reading would block before that. Bodies larger than `max_size` or
+/// shorter than their Content-Length are rejected.
+pub fn read_body<R: FnMut(&mut [u8]) -> io::Result<usize>>(mut read: R, body: Body, max_size: usize, buf: &mut Vec<u8>) -> Result<Option<Vec<u8>>, ApiError> {
+    let mut chunk = [0; READ_SIZE];
+
+    loop {
+        match read(&mut chunk) {
+            Ok(0) => {
+                // Only publish what the publisher said it would send
+                if let Body::Len(len) = body {
+                    if len > buf.len() as u64 {
+                        info!("Publish body ended after {} of {} bytes", buf.len(), len);
+
+                        return Err(ApiError::invalid_message("Body is shorter than its Content-Length".to_owned()));
                     }
+                }
 
-                    _ => None
-                };
+                return Ok(Some(mem::replace(buf, Vec::new())));
+            }
 
-                debug!("Found JWT parameter of {:?}", token);
+            Ok(n) => {
+                // Chunked bodies are only known to be too large once read
+                if buf.len() + n > max_size {
+                    info!("Publish body exceeds {} bytes", max_size);
 
-                match request.method() {
-                    &Get if path == "/stats" => {
-                        debug!("Processing /stats requests");
+                    return Err(ApiError::too_large(max_size));
+                }
 
-                        if self.access.is_authenticated_for_publish(self.topic.id.clone(), token) {
-                            self.route = Route::Stats;
-                        }
+                buf.extend_from_slice(&chunk[..n]);
 
-                        Next::write()
-                    }
+                match body {
+                    Body::Len(len) if len <= buf.len() as u64 => return Ok(Some(mem::replace(buf, Vec::new()))),
+                    _ => ()
+                }
+            }
 
-                    &Get if path.starts_with("/subscribe") => {
-                        if self.access.is_authenticated_for_subscribe(self.topic.id.clone(), token) {
-                            match Topic::validate(11, path.clone()) {
-                                Some(topic) => {
-                                    self.topic = topic;
-                                    self.route = Route::Subscribe;
-                                }
+            Err(ref e) if e.kind() == BlockingErr => return Ok(None),
 
-                                None => ()
-                            }
-                        }
+            Err(e) => {
+                warn!("Transport IO Error; err={:?}", e);
 
-                        Next::write()
-                    }
+                return Err(ApiError::invalid_message("Body could not be read".to_owned()));
+            }
+        }
+    }
+}
 
-                    &Post if path.starts_with("/publish") => {
-                        if self.access.is_authenticated_for_publish(self.topic.id.clone(), token) {
-                            match Topic::validate(9, path.clone()) {
-                                Some(topic) => {
-                                    let mut body_left = true;
-                                    let body = if let Some(len) = request.headers().get::<ContentLength>() {
-                                        body_left = **len > 0;
+/// Parses the events of a publish body and publishes them to the topic.
+/// Bodies that do not hold valid events are rejected with a 400.
+pub fn publish_body(manager: &Manager, topic: Topic, body: &[u8], format: Format, binary: BinaryPolicy) -> Result<Vec<Receipt>, ApiError> {
+    let events = match format {
+        Format::Text => match (str::from_utf8(body).is_ok(), binary) {
+            (false, BinaryPolicy::Base64) => Ok(vec![Event::from_binary(body)]),
+            _ => Event::parse(body).map(|event| vec![event])
+        },
+        Format::Json => Event::from_json(body).map(|event| vec![event]),
+        Format::JsonArray => Event::from_json_array(body),
+        Format::NdJson => Event::from_ndjson(body)
+    };
+
+    match events {
+        Ok(events) => {
+            let frames: Vec<Vec<u8>> = events.iter().map(|event| event.to_frame()).collect();
+
+            manager.publish_batch(topic, &frames).map_err(|e| ApiError::invalid_message(e.description().to_owned()))
+        }
 
-                                        Body::Len(**len)
-                                    } else {
-                                        Body::Chunked
-                                    };
+        Err(e) => Err(ApiError::invalid_message(e))
+    }
+}
 
-                                    self.topic = topic;
-                                    self.route = Route::Publish(body);
+// Fan-out bodies are a JSON event with an additional list of topic IDs
+fn parse_fan_out(buf: &[u8], rules: &TopicRules) -> Result<(Vec<Topic>, Event), ApiError> {
+    let json = match str::from_utf8(buf).ok().and_then(|text| Json::from_str(text).ok()) {
+        Some(json) => json,
+        None => return Err(ApiError::invalid_message("Body must be a valid JSON object".to_owned()))
+    };
+
+    let mut topics = Vec::new();
+
+    match json.find("topics") {
+        Some(&Json::Array(ref ids)) if ids.len() > 0 => {
+            for id in ids {
+                match id.as_string().map(|id| Topic::parse(id, rules)) {
+                    Some(Ok(topic)) => topics.push(topic),
+                    Some(Err(e)) => return Err(ApiError::from_topic(e)),
+                    None => return Err(ApiError::invalid_message(format!("Invalid topic {}", id)))
+                }
+            }
+        }
 
-                                    if body_left {
-                                        return Next::read_and_write();
-                                    }
-                                }
+        _ => return Err(ApiError::invalid_message("Body must contain a non-empty topics array".to_owned()))
+    }
 
-                                None => ()
-                            }
-                        }
+    let event = try!(Event::from_json_value(&json).map_err(ApiError::invalid_message));
 
-                        Next::write()
-                    }
+    Ok((topics, event))
+}
 
-                    _ => Next::write()
-                }
+// Parses a comma separated list of topic IDs; every ID must be valid
+fn parse_topic_list(ids: &str, rules: &TopicRules) -> Result<Vec<Topic>, TopicError> {
+    let mut topics: Vec<Topic> = Vec::new();
+
+    for id in ids.split(',').filter(|id| !id.is_empty()) {
+        let topic = try!(Topic::parse(id, rules));
+
+        if !topics.contains(&topic) {
+            topics.push(topic);
+        }
+    }
+
+    match topics.is_empty() {
+        true => Err(TopicError::Missing),
+        false => Ok(topics)
+    }
+}
+
+// Sets the status and headers of an error response, returning its body
+fn error_response(response: &mut Response, err: &ApiError) -> Vec<u8> {
+    response.set_status(err.status);
+    response.headers_mut().set(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])));
+
+    if let Some(ref method) = err.allow {
+        response.headers_mut().set(Allow(vec![method.clone()]));
+    }
+
+    let body = match err.to_json() {
+        Ok(json) => json.into_bytes(),
+        Err(e) => {
+            warn!("JSON Error; err={:?}", e);
+
+            Vec::new()
+        }
+    };
+
+    response.headers_mut().set(ContentLength(body.len() as u64));
+
+    body
+}
+
+// Logs why a request was not authorized, naming the claim that failed
+fn auth_error(err: AuthError) -> ApiError {
+    info!("Request not authorized; {} (claim: {:?})", err, err.claim());
+
+    ApiError::from_auth(err)
+}
+
+/// What a request is for once its topics are parsed and authorized
+#[derive(Debug)]
+pub enum Target {
+    Stats,
+    /// Subscribing to a single topic
+    Topic(Topic),
+    /// Subscribing to several topics at once
+    Topics(Vec<Topic>),
+    Pattern(Pattern),
+    Publish(Topic),
+    /// Publishing to the topics listed in the body, which are authorized
+    /// with the token once the body is read
+    FanOut(Option<String>)
+}
+
+/// Parses the topics of the endpoint, then checks the token grants access
+/// to them. Claims are compared to the parsed, normalized topic IDs. The
+/// `topics` are those of the query, for subscriptions without topics in
+/// the path.
+pub fn authorize_request(endpoint: &Endpoint, topics: Option<String>, token: Option<String>, rules: &TopicRules, access: &Access) -> Result<Target, ApiError> {
+    match *endpoint {
+        Endpoint::Stats => {
+            try!(access.authorize_stats(token).map_err(auth_error));
+
+            Ok(Target::Stats)
+        }
+
+        Endpoint::Subscribe(ref ids) if ids.is_empty() || ids.contains(',') => {
+            let ids = match ids.is_empty() {
+                true => topics.unwrap_or(String::new()),
+                false => ids.clone()
+            };
+
+            let topics = try!(parse_topic_list(&ids, rules).map_err(ApiError::from_topic));
+
+            {
+                let topic_ids: Vec<&str> = topics.iter().map(|topic| topic.id()).collect();
+
+                try!(access.authorize_subscribe(&topic_ids, token).map_err(auth_error));
             }
 
-            _ => Next::write()
+            Ok(Target::Topics(topics))
+        }
+
+        Endpoint::Subscribe(ref id) if id.contains('*') => {
+            let pattern = try!(Pattern::parse(id, rules).map_err(ApiError::from_topic));
+
+            try!(access.authorize_subscribe(&[pattern.id()], token).map_err(auth_error));
+
+            Ok(Target::Pattern(pattern))
+        }
+
+        Endpoint::Subscribe(ref id) => {
+            let topic = try!(Topic::parse(id, rules).map_err(ApiError::from_topic));
+
+            try!(access.authorize_subscribe(&[topic.id()], token).map_err(auth_error));
+
+            Ok(Target::Topic(topic))
+        }
+
+        Endpoint::Publish(ref id) | Endpoint::PublishBatch(ref id) => {
+            let topic = try!(Topic::parse(id, rules).map_err(ApiError::from_topic));
+
+            try!(access.authorize_publish(&[topic.id()], token).map_err(auth_error));
+
+            Ok(Target::Publish(topic))
+        }
+
+        Endpoint::FanOut => {
+            // Missing or invalid tokens are refused before the body is read,
+            // only the topics have to wait for it
+            try!(access.authorize_publish(&[], token.clone()).map_err(auth_error));
+
+            Ok(Target::FanOut(token))
         }
     }
+}
 
-    fn on_request_readable(&mut self, transport: &mut Decoder<HttpStream>) -> Next {
-        match self.route {
-            Route::Publish(ref body) => {
-                debug!("POST /publish req_readable");
+struct Params {
+    token: Option<String>,
+    last_event_id: Option<String>,
+    topics: Option<String>
+}
 
-                if self.msg_pos < self.msg_buf.len() {
-                    match transport.read(&mut self.msg_buf[self.msg_pos..]) {
-                        Ok(n) => {
-                            self.msg_pos += n;
+// The header as text; several `Cookie` lines are joined like a single one
+fn raw_header(request: &Request<HttpStream>, name: &str) -> Option<String> {
+    request.headers().get_raw(name).map(|lines| {
+        let lines: Vec<String> = lines.iter().map(|line| String::from_utf8_lossy(line).into_owned()).collect();
 
-                            match *body {
-                                Body::Len(max) if max > self.msg_pos as u64 => {
-                                    Next::read_and_write()
-                                }
+        lines.join("; ")
+    })
+}
 
-                                _ => Next::write()
-                            }
-                        }
+fn find_param(params: Parse, name: &str) -> Option<String> {
+    for (key, value) in params {
+        if key == name {
+            return Some(value.into_owned());
+        }
+    }
+
+    None
+}
+
+impl EventStream {
+    // Checks the request against the endpoint, returning the route to serve
+    fn dispatch(&mut self, endpoint: Endpoint, request: &Request<HttpStream>, params: Params) -> Result<Route, ApiError> {
+        let target = try!(authorize_request(&endpoint, params.topics, params.token, &self.options.topic_rules, &self.access));
 
-                        Err(e) => match e.kind() {
-                            BlockingErr => Next::read_and_write(),
+        match target {
+            Target::Stats => {
+                debug!("Processing /stats requests");
 
-                            _ => Next::end()
-                        }
+                Ok(Route::Stats)
+            }
+
+            Target::Topics(topics) => {
+                self.topics = topics;
+
+                Ok(Route::Subscribe)
+            }
+
+            Target::Pattern(pattern) => {
+                self.pattern = Some(pattern);
+
+                Ok(Route::Subscribe)
+            }
+
+            Target::Topic(topic) => {
+                // The header sent by a reconnecting EventSource wins over the query
+                self.last_event_id = match request.headers().get::<LastEventId>() {
+                    Some(header) => Some((**header).clone()),
+                    None => params.last_event_id
+                };
+
+                self.topic = topic;
+
+                Ok(Route::Subscribe)
+            }
+
+            Target::Publish(topic) => {
+                let batch = match endpoint {
+                    Endpoint::PublishBatch(_) => true,
+                    _ => false
+                };
+
+                let format = match (batch, request.headers().get::<ContentType>()) {
+                    (true, Some(&ContentType(Mime(TopLevel::Application, SubLevel::Ext(ref ext), _)))) if ext == "x-ndjson" => Format::NdJson,
+                    (true, _) => Format::JsonArray,
+                    (false, Some(&ContentType(Mime(TopLevel::Application, SubLevel::Json, _)))) => Format::Json,
+                    (false, _) => Format::Text
+                };
+
+                self.topic = topic;
+
+                let body = try!(find_body(request, self.options.max_body_size));
+
+                Ok(Route::Publish(body, format))
+            }
+
+            Target::FanOut(token) => {
+                // Topics are only known once the body is read; authorize then
+                self.token = token;
+
+                let body = try!(find_body(request, self.options.max_body_size));
+
+                Ok(Route::FanOut(body))
+            }
+        }
+    }
+}
+
+impl Handler<HttpStream> for EventStream {
+    fn on_request(&mut self, request: Request<HttpStream>) -> Next {
+        let route = match *request.uri() {
+            RequestUri::AbsolutePath { ref path, ref query } => {
+                // The query is left out as it may hold a token
+                info!("{} {}", request.method(), path);
+
+                let mut params = match *query {
+                    Some(ref qs) => Params {
+                        token: find_param(form_urlencoded_parse(qs.as_bytes()), "token"),
+                        last_event_id: find_param(form_urlencoded_parse(qs.as_bytes()), "lastEventId"),
+                        topics: find_param(form_urlencoded_parse(qs.as_bytes()), "topics")
+                    },
+
+                    _ => Params {
+                        token: None,
+                        last_event_id: None,
+                        topics: None
                     }
-                } else {
-                    Next::write()
+                };
+
+                let authorization = raw_header(&request, "Authorization");
+                let cookies = raw_header(&request, "Cookie");
+
+                params.token = self.access.find_token(authorization.as_ref().map(|h| h.as_str()),
+                                                      cookies.as_ref().map(|h| h.as_str()),
+                                                      params.token.take());
+
+                debug!("Found JWT: {}", params.token.is_some());
+
+                match (self.manager.is_closing(), router::route(request.method(), path)) {
+                    (true, _) => Err(ApiError::shutting_down()),
+                    (false, Ok(endpoint)) => self.dispatch(endpoint, &request, params),
+                    (false, Err(e)) => Err(ApiError::from_route(e))
                 }
             }
 
+            _ => {
+                info!("{} {}", request.method(), log_uri(request.uri()));
+
+                Err(ApiError::from_route(RouteError::NotFound))
+            }
+        };
+
+        self.route = match route {
+            Ok(route) => route,
+            Err(e) => {
+                info!("Rejected {} {}; err={:?}", request.method(), log_uri(request.uri()), e);
+
+                Route::Error(e)
+            }
+        };
+
+        match self.route {
+            Route::Publish(Body::Len(0), _) | Route::FanOut(Body::Len(0)) => Next::write(),
+            Route::Publish(..) | Route::FanOut(_) => Next::read(),
+            _ => Next::write()
+        }
+    }
+
+    fn on_request_readable(&mut self, transport: &mut Decoder<HttpStream>) -> Next {
+        let body = match self.route {
+            Route::Publish(body, _) | Route::FanOut(body) => body,
             _ => unreachable!()
+        };
+
+        debug!("POST /publish req_readable");
+
+        match read_body(|chunk| transport.read(chunk), body, self.options.max_body_size, &mut self.msg_buf) {
+            Ok(Some(buf)) => {
+                self.msg_buf = buf;
+
+                Next::write()
+            }
+
+            Ok(None) => Next::read(),
+
+            Err(e) => {
+                self.route = Route::Error(e);
+
+                Next::write()
+            }
         }
     }
 
     fn on_response(&mut self, response: &mut Response) -> Next {
         match self.route {
-            Route::Publish(_) => {
+            Route::Publish(_, format) => {
                 debug!("POST /publish on_response");
 
-                match self.manager.lock() {
-                    Ok(mut mgr) => {
-                        mgr.publish(self.topic.clone(), &self.msg_buf);
+                match publish_body(&self.manager, self.topic.clone(), &self.msg_buf, format, self.options.binary) {
+                    Ok(receipts) => {
+                        let subscribed = receipts.iter().any(|receipt| receipt.subscribers > 0);
+
+                        response.set_status(self.options.no_subscribers.status(subscribed));
+                        response.headers_mut().set(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])));
+
+                        // Batches respond with every receipt, single events with just theirs
+                        let encoded = match format {
+                            Format::JsonArray | Format::NdJson => json::encode(&receipts),
+                            Format::Text | Format::Json => json::encode(&receipts[0])
+                        };
+
+                        match encoded {
+                            Ok(json) => self.out_buf = json.into_bytes(),
+                            Err(e) => warn!("JSON Error; err={:?}", e)
+                        }
                     }
 
-                    Err(_) => warn!("Failed to lock manager")
+                    Err(e) => {
+                        debug!("Publish rejected; err={:?}", e);
+
+                        self.out_buf = error_response(response, &e);
+
+                        return Next::write();
+                    }
                 }
 
-                Next::end()
+                response.headers_mut().set(ContentLength(self.out_buf.len() as u64));
+
+                Next::write()
+            }
+
+            Route::FanOut(_) => {
+                debug!("POST /publish fan-out on_response");
+
+                let (topics, event) = match parse_fan_out(&self.msg_buf, &self.options.topic_rules) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        debug!("Fan-out publish rejected; err={:?}", e);
+
+                        self.out_buf = error_response(response, &e);
+
+                        return Next::write();
+                    }
+                };
+
+                let authorized = {
+                    let topic_ids: Vec<&str> = topics.iter().map(|topic| topic.id()).collect();
+
+                    self.access.authorize_publish(&topic_ids, self.token.take())
+                };
+
+                if let Err(e) = authorized {
+                    self.out_buf = error_response(response, &auth_error(e));
+
+                    return Next::write();
+                }
+
+                match self.manager.publish_to(&topics, &event.to_frame()) {
+                    Ok(receipts) => {
+                        let subscribed = receipts.iter().any(|receipt| receipt.subscribers > 0);
+
+                        response.set_status(self.options.no_subscribers.status(subscribed));
+                        response.headers_mut().set(ContentType(Mime(TopLevel::Application, SubLevel::Json, vec![])));
+
+                        match json::encode(&receipts) {
+                            Ok(json) => self.out_buf = json.into_bytes(),
+                            Err(e) => warn!("JSON Error; err={:?}", e)
+                        }
+                    }
+
+                    Err(e) => {
+                        self.out_buf = error_response(response, &ApiError::invalid_message(e.description().to_owned()));
+
+                        return Next::write();
+                    }
+                }
+
+                response.headers_mut().set(ContentLength(self.out_buf.len() as u64));
+
+                Next::write()
             }
 
             Route::Subscribe => {
@@ -195,56 +601,58 @@ impl Handler<HttpStream> for EventStream {
 
                 response.headers_mut().set(ContentType(Mime(TopLevel::Text, SubLevel::EventStream, vec![])));
 
-                match self.manager.lock() {
-                    Ok(mut mgr) => {
-                        mgr.subscribe(self.id.clone(), self.topic.clone(), self.control.clone());
+                let greeting = match self.pattern {
+                    Some(ref pattern) => {
+                        self.manager.subscribe_pattern(self.id.clone(), pattern.clone(), self.control.clone());
 
-                        Next::wait()
+                        self.options.greeting_for(pattern.id())
                     }
 
-                    Err(_) => {
-                        warn!("Failed to lock manager!");
+                    None if self.topics.len() > 0 => {
+                        self.manager.subscribe_all(self.id.clone(), &self.topics, self.control.clone());
 
-                        Next::end()
+                        &self.options.greeting
                     }
-                }
+
+                    None => {
+                        let last_event_id = self.last_event_id.take();
+
+                        self.manager.subscribe(self.id.clone(), self.topic.clone(), self.control.clone(), last_event_id);
+
+                        self.options.greeting_for(self.topic.id())
+                    }
+                };
+
+                // Written before any queued messages, see on_response_writable
+                self.out_buf = greeting.to_frame();
+
+                Next::write()
             }
 
             Route::Stats => {
                 debug!("GET /stats on_response");
 
-                match self.manager.lock() {
-                    Ok(mgr) => {
-                        match mgr.stats_json() {
-                            Ok(json) => {
-                                response.headers_mut().set(ContentLength(json.len() as u64));
+                match self.manager.stats_json() {
+                    Ok(json) => {
+                        response.headers_mut().set(ContentLength(json.len() as u64));
 
-                                self.out_buf = json.into_bytes();
-
-                                Next::write()
-                            }
+                        self.out_buf = json.into_bytes();
 
-                            Err(e) => {
-                                warn!("JSON Error; err={:?}", e);
-
-                                Next::end()
-                            }
-                        }
+                        Next::write()
                     }
 
-                    Err(_) => {
-                        warn!("Failed to lock manager!");
+                    Err(e) => {
+                        warn!("JSON Error; err={:?}", e);
 
                         Next::end()
                     }
                 }
             }
 
-            Route::NotFound => {
-                debug!("Route Not Found on_response");
+            Route::Error(ref e) => {
+                debug!("Error on_response; err={:?}", e);
 
-                response.set_status(StatusCode::NotFound);
-                response.headers_mut().set(ContentLength(NOT_FOUND.len() as u64));
+                self.out_buf = error_response(response, e);
 
                 Next::write()
             }
@@ -254,66 +662,87 @@ impl Handler<HttpStream> for EventStream {
     fn on_response_writable(&mut self, transport: &mut Encoder<HttpStream>) -> Next {
         match self.route {
             Route::Subscribe => {
-                match self.manager.lock() {
-                    Ok(mut mgr) => {
-                        let msgs = mgr.messages_for(self.id.clone());
-
-                        for msg in msgs.iter() {
-                            match transport.write(msg.as_slice()) {
-                                Ok(_) => debug!("Transport wrote message"),
-                                Err(e) => {
-                                    warn!("Transport IO Error; err={:?}", e);
-
-                                    return Next::end()
-                                }
+                // The greeting, or what is left of a message, goes out in
+                // full before anything else
+                if self.out_buf.len() > 0 {
+                    match transport.write(self.out_buf.as_slice()) {
+                        Ok(n) => {
+                            self.out_buf.drain(..n);
+
+                            if self.out_buf.len() > 0 {
+                                return Next::write()
                             }
                         }
 
-                        Next::wait()
+                        Err(ref e) if e.kind() == BlockingErr => return Next::write(),
+
+                        Err(e) => {
+                            warn!("Transport IO Error; err={:?}", e);
+
+                            return Next::end()
+                        }
+                    }
+                }
+
+                // Whatever the transport could not take stays queued, only
+                // the tail of a partially written message is kept here
+                match self.manager.write_messages(self.id.clone(), |buf| transport.write(buf)) {
+                    Ok(None) => debug!("Transport wrote messages"),
+
+                    Ok(Some(tail)) => {
+                        self.out_buf = tail;
+
+                        return Next::write()
                     }
 
-                    Err(_) => Next::end()
+                    Err(e) => {
+                        warn!("Transport IO Error; err={:?}", e);
+
+                        return Next::end()
+                    }
+                }
+
+                // Disconnect once the final message of a shutdown is written
+                match self.manager.is_finished(self.id.clone()) {
+                    true => Next::end(),
+                    false => Next::wait()
                 }
             }
 
-            Route::Stats => {
+            Route::Publish(..) | Route::FanOut(_) | Route::Stats | Route::Error(_) => {
                 if self.out_buf.len() > 0 {
-                    transport.write(self.out_buf.as_slice()).unwrap();
-                }
+                    match transport.write(self.out_buf.as_slice()) {
+                        // Write the rest once the transport is writable again
+                        Ok(n) if n < self.out_buf.len() => {
+                            self.out_buf.drain(..n);
 
-                Next::end()
-            }
+                            return Next::write()
+                        }
+
+                        Ok(_) => self.out_buf.clear(),
+                        Err(ref e) if e.kind() == BlockingErr => return Next::write(),
+
+                        Err(e) => {
+                            warn!("Transport IO Error; err={:?}", e);
 
-            Route::NotFound => {
-                transport.write(NOT_FOUND).unwrap();
+                            return Next::end()
+                        }
+                    }
+                }
 
                 Next::end()
             }
-
-            _ => unreachable!()
         }
     }
 
     fn on_error(&mut self, _err: Error) -> Next {
-        match self.manager.lock() {
-            Ok(mut mgr) => {
-                mgr.unsubscribe(self.id.clone(), self.topic.clone());
-            }
-
-            Err(_) => warn!("Failed to lock manager")
-        }
+        self.manager.unsubscribe_client(self.id.clone());
 
         Next::end()
     }
 
     fn on_remove(self, _transport: HttpStream) -> () {
-        match self.manager.lock() {
-            Ok(mut mgr) => {
-                mgr.unsubscribe(self.id.clone(), self.topic.clone());
-            }
-
-            Err(_) => warn!("Failed to lock manager")
-        }
+        self.manager.unsubscribe_client(self.id.clone());
     }
 }
 
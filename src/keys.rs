@@ -0,0 +1,439 @@
+extern crate ring;
+extern crate time;
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard};
+
+use self::ring::{hmac, signature};
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::Json;
+
+// DER tags and the object identifiers of the supported public keys
+const SEQUENCE: u8 = 0x30;
+const BIT_STRING: u8 = 0x03;
+const INTEGER: u8 = 0x02;
+const OID: u8 = 0x06;
+
+const RSA_ENCRYPTION: &'static [u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const EC_PUBLIC_KEY: &'static [u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const PRIME256V1: &'static [u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const ED25519: &'static [u8] = &[0x2b, 0x65, 0x70];
+
+/// Why a key file could not be loaded
+#[derive(Debug, PartialEq)]
+pub enum KeyError {
+    Io(String),
+    InvalidPem(String),
+    InvalidJwks(String),
+    NoKeys
+}
+
+impl KeyError {
+    pub fn description(&self) -> String {
+        match *self {
+            KeyError::Io(ref err) => format!("Key file could not be read; {}", err),
+            KeyError::InvalidPem(ref err) => format!("Invalid PEM key; {}", err),
+            KeyError::InvalidJwks(ref err) => format!("Invalid JWKS; {}", err),
+            KeyError::NoKeys => "Key file holds no usable keys".to_owned()
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Material {
+    Hmac(Vec<u8>),
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    Ecdsa(Vec<u8>),
+    Ed25519(Vec<u8>)
+}
+
+/// A key tokens are verified with. Keys from a JWKS carry the `kid` tokens
+/// refer to them by and may be retired at an `exp` timestamp.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Key {
+    kid: Option<String>,
+    expires: Option<i64>,
+    material: Material
+}
+
+impl Key {
+    /// A shared secret for HS256 tokens
+    pub fn secret(secret: &str) -> Key {
+        Key {
+            kid: None,
+            expires: None,
+            material: Material::Hmac(secret.as_bytes().to_vec())
+        }
+    }
+
+    pub fn kid(&self) -> Option<&str> {
+        self.kid.as_ref().map(|kid| kid.as_str())
+    }
+
+    /// Whether the key is still accepted at the given time in seconds
+    pub fn is_valid_at(&self, now: i64) -> bool {
+        self.expires.map_or(true, |expires| now < expires)
+    }
+
+    /// The JWS algorithm of tokens signed with this key
+    pub fn algorithm(&self) -> &'static str {
+        match self.material {
+            Material::Hmac(_) => "HS256",
+            Material::Rsa { .. } => "RS256",
+            Material::Ecdsa(_) => "ES256",
+            Material::Ed25519(_) => "EdDSA"
+        }
+    }
+
+    pub fn verify(&self, message: &[u8], sig: &[u8]) -> bool {
+        let verified = match self.material {
+            Material::Hmac(ref secret) => {
+                hmac::verify(&hmac::Key::new(hmac::HMAC_SHA256, secret), message, sig)
+            }
+
+            Material::Rsa { ref n, ref e } => {
+                signature::RsaPublicKeyComponents { n: n, e: e }.verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+            }
+
+            Material::Ecdsa(ref point) => {
+                signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point).verify(message, sig)
+            }
+
+            Material::Ed25519(ref key) => {
+                signature::UnparsedPublicKey::new(&signature::ED25519, key).verify(message, sig)
+            }
+        };
+
+        verified.is_ok()
+    }
+}
+
+/// The keys a token may be signed with
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeySet {
+    keys: Vec<Key>
+}
+
+impl KeySet {
+    pub fn new() -> KeySet {
+        KeySet::default()
+    }
+
+    pub fn secret(secret: &str) -> KeySet {
+        KeySet {
+            keys: vec![Key::secret(secret)]
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn extend(&mut self, other: KeySet) {
+        self.keys.extend(other.keys);
+    }
+
+    /// Parses a JWKS document or one or more PEM encoded public keys
+    pub fn parse(contents: &str) -> Result<KeySet, KeyError> {
+        match contents.trim_left().starts_with('{') {
+            true => KeySet::from_jwks(contents),
+            false => KeySet::from_pem(contents)
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<KeySet, KeyError> {
+        let mut contents = String::new();
+
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut contents)).map_err(|e| {
+            KeyError::Io(format!("{}: {}", path.display(), e))
+        }));
+
+        KeySet::parse(&contents)
+    }
+
+    /// Parses `PUBLIC KEY` blocks holding RSA, P-256 or Ed25519 keys and
+    /// `RSA PUBLIC KEY` blocks
+    pub fn from_pem(contents: &str) -> Result<KeySet, KeyError> {
+        let mut keys = Vec::new();
+        let mut block: Option<(&str, String)> = None;
+
+        for line in contents.lines().map(|line| line.trim()) {
+            if line.starts_with("-----BEGIN ") {
+                block = Some((line.trim_matches('-').trim_left_matches("BEGIN "), String::new()));
+            } else if line.starts_with("-----END ") {
+                let (label, body) = match block.take() {
+                    Some(block) => block,
+                    None => return Err(KeyError::InvalidPem("END line without BEGIN line".to_owned()))
+                };
+
+                let der = try!(body.from_base64().map_err(|_| KeyError::InvalidPem(format!("{} is not base64", label))));
+
+                let material = match label {
+                    "PUBLIC KEY" => from_spki(&der),
+                    "RSA PUBLIC KEY" => from_pkcs1(&der),
+                    _ => return Err(KeyError::InvalidPem(format!("unsupported block {}", label)))
+                };
+
+                match material {
+                    Ok(material) => keys.push(Key { kid: None, expires: None, material: material }),
+                    Err(_) => return Err(KeyError::InvalidPem(format!("unsupported or malformed {}", label)))
+                }
+            } else if let Some((_, ref mut body)) = block {
+                body.push_str(line);
+            }
+        }
+
+        match keys.is_empty() {
+            true => Err(KeyError::NoKeys),
+            false => Ok(KeySet { keys: keys })
+        }
+    }
+
+    /// Parses the `keys` of a JWKS document. Keys of unsupported types or
+    /// meant for encryption are skipped. Shared secrets are given as `oct`
+    /// keys, so a current and a previous secret can be told apart by `kid`.
+    pub fn from_jwks(contents: &str) -> Result<KeySet, KeyError> {
+        let json = try!(Json::from_str(contents).map_err(|e| KeyError::InvalidJwks(format!("{}", e))));
+
+        let jwks = match json.find("keys").and_then(|keys| keys.as_array()) {
+            Some(jwks) => jwks,
+            None => return Err(KeyError::InvalidJwks("no keys array".to_owned()))
+        };
+
+        let mut keys = Vec::new();
+
+        for jwk in jwks {
+            match from_jwk(jwk) {
+                Ok(key) => keys.push(key),
+                Err(reason) => warn!("Skipping JWK {:?}; {}", jwk_field(jwk, "kid"), reason)
+            }
+        }
+
+        match keys.is_empty() {
+            true => Err(KeyError::NoKeys),
+            false => Ok(KeySet { keys: keys })
+        }
+    }
+
+    /// Whether one of the keys for the algorithm verifies the signature,
+    /// trying them in order. Keys named by a different `kid` than the
+    /// token's and retired keys are not tried.
+    pub fn verify(&self, algorithm: &str, kid: Option<&str>, message: &[u8], sig: &[u8]) -> bool {
+        let now = time::get_time().sec;
+
+        self.keys.iter()
+            .filter(|key| key.algorithm() == algorithm && key.is_valid_at(now))
+            .filter(|key| match (key.kid(), kid) {
+                (Some(key_id), Some(kid)) => key_id == kid,
+                _ => true
+            })
+            .any(|key| key.verify(message, sig))
+    }
+}
+
+/// The keys of one role: its shared secret plus the keys of a PEM or JWKS
+/// file, which can be reloaded while running
+#[derive(Debug)]
+pub struct KeyStore {
+    secret: String,
+    path: Option<PathBuf>,
+    keys: RwLock<KeySet>
+}
+
+impl KeyStore {
+    pub fn new(secret: String) -> KeyStore {
+        let keys = KeyStore::combine(&secret, KeySet::new());
+
+        KeyStore {
+            secret: secret,
+            path: None,
+            keys: RwLock::new(keys)
+        }
+    }
+
+    /// Adds the keys of the file, failing if it cannot be loaded
+    pub fn with_file(self, path: PathBuf) -> Result<KeyStore, KeyError> {
+        let keys = KeyStore::combine(&self.secret, try!(KeySet::load(&path)));
+
+        Ok(KeyStore {
+            path: Some(path),
+            keys: RwLock::new(keys),
+            ..self
+        })
+    }
+
+    fn combine(secret: &str, file: KeySet) -> KeySet {
+        let mut keys = match secret.is_empty() {
+            true => KeySet::new(),
+            false => KeySet::secret(secret)
+        };
+
+        keys.extend(file);
+
+        keys
+    }
+
+    /// Whether tokens are required, i.e. a secret or key file is set
+    pub fn is_enabled(&self) -> bool {
+        !self.secret.is_empty() || self.path.is_some()
+    }
+
+    pub fn keys(&self) -> RwLockReadGuard<KeySet> {
+        self.keys.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Reads the key file again, keeping the current keys if it cannot be
+    /// loaded. Returns the number of keys.
+    pub fn reload(&self) -> Result<usize, KeyError> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(self.keys().len())
+        };
+
+        let keys = KeyStore::combine(&self.secret, try!(KeySet::load(path)));
+        let len = keys.len();
+
+        *self.keys.write().unwrap_or_else(|e| e.into_inner()) = keys;
+
+        Ok(len)
+    }
+
+    pub fn has_file(&self) -> bool {
+        self.path.is_some()
+    }
+}
+
+// Splits the DER element with the given tag off the input, returning its
+// contents and the remaining input
+fn der(input: &[u8], tag: u8) -> Result<(&[u8], &[u8]), ()> {
+    if input.len() < 2 || input[0] != tag {
+        return Err(());
+    }
+
+    let (len, header) = match input[1] {
+        len if len < 0x80 => (len as usize, 2),
+        len => {
+            let count = (len & 0x7f) as usize;
+
+            if count == 0 || count > 4 || input.len() < 2 + count {
+                return Err(());
+            }
+
+            (input[2..2 + count].iter().fold(0, |len, &b| (len << 8) | b as usize), 2 + count)
+        }
+    };
+
+    match header.checked_add(len) {
+        Some(end) if end <= input.len() => Ok((&input[header..end], &input[end..])),
+        _ => Err(())
+    }
+}
+
+// Big-endian integers are given to ring without their sign padding
+fn unsigned(bytes: &[u8]) -> Vec<u8> {
+    bytes.iter().skip_while(|&&b| b == 0).cloned().collect()
+}
+
+// A PKCS#1 RSAPublicKey, a sequence of the modulus and exponent
+fn from_pkcs1(input: &[u8]) -> Result<Material, ()> {
+    let (key, _) = try!(der(input, SEQUENCE));
+    let (n, rest) = try!(der(key, INTEGER));
+    let (e, _) = try!(der(rest, INTEGER));
+
+    Ok(Material::Rsa {
+        n: unsigned(n),
+        e: unsigned(e)
+    })
+}
+
+// A SubjectPublicKeyInfo, the algorithm identifier and the key as a bit string
+fn from_spki(input: &[u8]) -> Result<Material, ()> {
+    let (spki, _) = try!(der(input, SEQUENCE));
+    let (algorithm, rest) = try!(der(spki, SEQUENCE));
+    let (bits, _) = try!(der(rest, BIT_STRING));
+    let (oid, params) = try!(der(algorithm, OID));
+
+    // The first byte counts the unused bits of the last byte
+    let key = match bits.split_first() {
+        Some((&0, key)) => key,
+        _ => return Err(())
+    };
+
+    match oid {
+        RSA_ENCRYPTION => from_pkcs1(key),
+        EC_PUBLIC_KEY if try!(der(params, OID)).0 == PRIME256V1 => Ok(Material::Ecdsa(key.to_vec())),
+        ED25519 if key.len() == 32 => Ok(Material::Ed25519(key.to_vec())),
+        _ => Err(())
+    }
+}
+
+fn jwk_field<'a>(jwk: &'a Json, name: &str) -> Option<&'a str> {
+    jwk.find(name).and_then(|value| value.as_string())
+}
+
+fn jwk_bytes(jwk: &Json, name: &str) -> Result<Vec<u8>, String> {
+    match jwk_field(jwk, name) {
+        Some(value) => value.from_base64().map_err(|_| format!("{} is not base64url", name)),
+        None => Err(format!("no {} member", name))
+    }
+}
+
+fn from_jwk(jwk: &Json) -> Result<Key, String> {
+    if jwk_field(jwk, "use") == Some("enc") {
+        return Err("key is meant for encryption".to_owned());
+    }
+
+    let material = match (jwk_field(jwk, "kty"), jwk_field(jwk, "crv")) {
+        (Some("RSA"), _) => Material::Rsa {
+            n: unsigned(&try!(jwk_bytes(jwk, "n"))),
+            e: unsigned(&try!(jwk_bytes(jwk, "e")))
+        },
+
+        (Some("EC"), Some("P-256")) => {
+            let (x, y) = (try!(jwk_bytes(jwk, "x")), try!(jwk_bytes(jwk, "y")));
+
+            if x.len() != 32 || y.len() != 32 {
+                return Err("P-256 coordinates must be 32 bytes".to_owned());
+            }
+
+            // Uncompressed point encoding
+            let mut point = vec![0x04];
+
+            point.extend(x);
+            point.extend(y);
+
+            Material::Ecdsa(point)
+        }
+
+        (Some("OKP"), Some("Ed25519")) => Material::Ed25519(try!(jwk_bytes(jwk, "x"))),
+        (Some("oct"), _) => Material::Hmac(try!(jwk_bytes(jwk, "k"))),
+        (kty, crv) => return Err(format!("unsupported key type {:?} with curve {:?}", kty, crv))
+    };
+
+    // Not a registered JWK member; lets a rotated out key expire on its own
+    let expires = match jwk.find("exp") {
+        Some(exp) => match exp.as_i64() {
+            Some(exp) => Some(exp),
+            None => return Err("exp must be a timestamp in seconds".to_owned())
+        },
+
+        None => None
+    };
+
+    let key = Key {
+        kid: jwk_field(jwk, "kid").map(|kid| kid.to_owned()),
+        expires: expires,
+        material: material
+    };
+
+    match jwk_field(jwk, "alg") {
+        Some(alg) if alg != key.algorithm() => Err(format!("unsupported algorithm {}", alg)),
+        _ => Ok(key)
+    }
+}
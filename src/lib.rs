@@ -1,23 +1,46 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
 use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 
 extern crate rustc_serialize;
+#[macro_use]
 extern crate hyper;
 extern crate url;
 extern crate uuid;
+extern crate regex;
 
 #[macro_use]
 extern crate log;
 
-use hyper::{Control, Next};
+use hyper::{Control, Next, StatusCode};
 use rustc_serialize::json::{self, EncodeResult};
 
 use uuid::Uuid;
+use regex::Regex;
 
 pub mod handler;
 pub mod auth;
+pub mod keys;
+pub mod message;
+pub mod pattern;
+pub mod router;
+pub mod queue;
+
+use auth::{authorize_with, AuthError, Role, Validation};
+use keys::{KeyError, KeyStore};
 
-use auth::authenticate;
+pub use message::Message;
+pub use pattern::{Pattern, PatternTrie};
+pub use queue::{OverflowPolicy, Queue, QueueLimits};
+
+use queue::Push;
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct Client {
@@ -32,6 +55,97 @@ impl Client {
     }
 }
 
+/// Rules a topic ID has to follow; loaded at startup and applied to every
+/// route taking a topic ID
+#[derive(Clone, Debug)]
+pub struct TopicRules {
+    /// Characters allowed besides letters and digits, e.g. `-_:/`
+    pub extra_chars: String,
+    pub min_len: usize,
+    pub max_len: usize,
+    /// When false, topic IDs are lowercased
+    pub case_sensitive: bool,
+    /// When set, the whole topic ID has to match the expression
+    pub regex: Option<Regex>
+}
+
+impl Default for TopicRules {
+    fn default() -> TopicRules {
+        TopicRules {
+            extra_chars: String::new(),
+            min_len: 8,
+            max_len: 64,
+            case_sensitive: false,
+            regex: None
+        }
+    }
+}
+
+impl TopicRules {
+    /// Compiles an expression that has to match topic IDs from start to end
+    pub fn full_match(expr: &str) -> Result<Regex, regex::Error> {
+        Regex::new(&format!("^(?:{})$", expr))
+    }
+
+    pub fn is_allowed(&self, c: char) -> bool {
+        c.is_alphanumeric() || self.extra_chars.contains(c)
+    }
+
+    pub fn normalize(&self, id: &str) -> String {
+        match self.case_sensitive {
+            true => id.to_owned(),
+            false => id.to_lowercase()
+        }
+    }
+
+    /// Checks the topic ID, returning it normalized
+    pub fn check(&self, id: &str) -> Result<String, TopicError> {
+        let len = id.chars().count();
+
+        if len == 0 {
+            return Err(TopicError::Missing);
+        } else if len < self.min_len {
+            return Err(TopicError::TooShort(self.min_len));
+        } else if len > self.max_len {
+            return Err(TopicError::TooLong(self.max_len));
+        }
+
+        match id.chars().find(|&c| !self.is_allowed(c)) {
+            Some(c) => return Err(TopicError::InvalidCharacter(c)),
+            None => ()
+        }
+
+        // The expression sees the ID as it was given, before lowercasing
+        match self.regex {
+            Some(ref regex) if !regex.is_match(id) => Err(TopicError::Mismatch(regex.as_str().to_owned())),
+            _ => Ok(self.normalize(id))
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TopicError {
+    Missing,
+    TooShort(usize),
+    TooLong(usize),
+    InvalidCharacter(char),
+    Mismatch(String),
+    NotAPattern
+}
+
+impl TopicError {
+    pub fn description(&self) -> String {
+        match *self {
+            TopicError::Missing => "No topic ID given".to_owned(),
+            TopicError::TooShort(min) => format!("Topic ID must be at least {} characters long", min),
+            TopicError::TooLong(max) => format!("Topic ID must be at most {} characters long", max),
+            TopicError::InvalidCharacter(c) => format!("Topic ID must not contain {:?}", c),
+            TopicError::Mismatch(ref expr) => format!("Topic ID must match {}", expr),
+            TopicError::NotAPattern => "Topic pattern must contain a wildcard".to_owned()
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Topic {
     id: Box<str>
@@ -45,141 +159,995 @@ impl Topic {
         }
     }
 
+    /// Validates the topic ID found after skipping the given number of
+    /// characters, using the default `TopicRules`
     pub fn validate(skip: usize, full_path: String) -> Option<Topic> {
         let id: String = full_path.chars().skip(skip).collect();
 
-        match (id.chars().all(|c| c.is_alphanumeric()), id.len()) {
-            (true, 8...64) => Some(Topic {
-                id: id.to_lowercase().into_boxed_str()
-            }),
+        Topic::parse(&id, &TopicRules::default()).ok()
+    }
 
-            _ => None
+    pub fn parse(id: &str, rules: &TopicRules) -> Result<Topic, TopicError> {
+        let id = try!(rules.check(id));
+
+        Ok(Topic {
+            id: id.into_boxed_str()
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &*self.id
+    }
+}
+
+pub struct History {
+    capacity: usize,
+    messages: VecDeque<Message>
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            capacity: capacity,
+            messages: VecDeque::with_capacity(capacity)
+        }
+    }
+
+    pub fn push(&mut self, msg: Message) -> () {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+
+        self.messages.push_back(msg);
+    }
+
+    // Returns every message published after the one with the given ID. When
+    // the ID is no longer retained, the whole history is considered newer.
+    pub fn since(&self, last_event_id: &str) -> Vec<Message> {
+        let skip = match self.messages.iter().rposition(|msg| msg.id() == Some(last_event_id)) {
+            Some(index) => index + 1,
+            None => 0
+        };
+
+        self.messages.iter().skip(skip).cloned().collect()
+    }
+}
+
+/// Decides who assigns the "id" field of published messages.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IdPolicy {
+    /// Messages are published as is; IDs are left to the publisher
+    Publisher,
+    /// Esper assigns IDs and replaces any supplied by the publisher
+    Override,
+    /// Esper assigns IDs and rejects messages that already carry one
+    Reject
+}
+
+impl FromStr for IdPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IdPolicy, String> {
+        match s {
+            "off" | "publisher" => Ok(IdPolicy::Publisher),
+            "override" => Ok(IdPolicy::Override),
+            "reject" => Ok(IdPolicy::Reject),
+            _ => Err(format!("Unknown event ID policy {:?}", s))
         }
     }
 }
 
-#[derive(Clone)]
-pub struct Message {
-    body: Vec<u8>
+/// How to respond to publishers when a topic has no subscribers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoSubscribers {
+    /// Respond with `202 Accepted` like any other publish
+    Accept,
+    /// Respond with `404 Not Found`
+    NotFound,
+    /// Respond with `410 Gone`
+    Gone
 }
 
-impl Message {
-    pub fn new(buf: &Vec<u8>) -> Message {
-        // Append Event Source delimiter ("\n\n")
-        let mut delimiter = vec![10 as u8, 10 as u8];
-        let mut delimited_body = buf.clone();
+impl NoSubscribers {
+    /// The status of a publish response given whether anyone was subscribed
+    pub fn status(&self, subscribed: bool) -> StatusCode {
+        match (subscribed, *self) {
+            (true, _) | (false, NoSubscribers::Accept) => StatusCode::Accepted,
+            (false, NoSubscribers::NotFound) => StatusCode::NotFound,
+            (false, NoSubscribers::Gone) => StatusCode::Gone
+        }
+    }
+}
 
-        delimited_body.append(&mut delimiter);
+impl FromStr for NoSubscribers {
+    type Err = String;
 
-        Message {
-            body: delimited_body
+    fn from_str(s: &str) -> Result<NoSubscribers, String> {
+        match s {
+            "accept" | "202" => Ok(NoSubscribers::Accept),
+            "404" => Ok(NoSubscribers::NotFound),
+            "410" => Ok(NoSubscribers::Gone),
+            _ => Err(format!("Unknown no subscribers status {:?}", s))
         }
     }
+}
+
+/// How to handle raw publish bodies that are not valid UTF-8
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryPolicy {
+    /// Reject the message with a `400`
+    Reject,
+    /// Publish the body base64 encoded as the data of an event
+    Base64
+}
+
+impl FromStr for BinaryPolicy {
+    type Err = String;
 
-    pub fn as_slice(&self) -> &[u8] {
-        self.body.as_slice()
+    fn from_str(s: &str) -> Result<BinaryPolicy, String> {
+        match s {
+            "reject" => Ok(BinaryPolicy::Reject),
+            "base64" => Ok(BinaryPolicy::Base64),
+            _ => Err(format!("Unknown binary policy {:?}", s))
+        }
+    }
+}
+
+/// The frame written to subscribers as soon as they connect. Writing right
+/// away makes proxies pass the response on and browsers fire `onopen`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Greeting {
+    /// Reconnection interval in milliseconds sent as the `retry` field
+    pub retry: Option<u64>,
+    /// Length of a comment sent ahead of everything else, for proxies and
+    /// old browsers that buffer the first few kilobytes of a response
+    pub padding: usize
+}
+
+impl Greeting {
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut frame = String::from(":");
+
+        for _ in 0..self.padding {
+            frame.push(' ');
+        }
+
+        frame.push('\n');
+
+        if let Some(retry) = self.retry {
+            frame.push_str(&format!("retry: {}\n", retry));
+        }
+
+        frame.push('\n');
+
+        frame.into_bytes()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub history_size: usize,
+    /// Topics whose history and event ID sequence are retained, the least
+    /// recently published to are forgotten first. 0 retains every topic.
+    pub history_topics: usize,
+    pub id_policy: IdPolicy,
+    pub topic_rules: TopicRules,
+    pub no_subscribers: NoSubscribers,
+    pub max_body_size: usize,
+    pub binary: BinaryPolicy,
+    pub queue_limits: QueueLimits,
+    pub heartbeat: Option<Duration>,
+    pub greeting: Greeting,
+    /// Greetings for topics matching an ID or pattern, the first match wins
+    pub topic_greetings: Vec<(String, Greeting)>
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            history_size: 100,
+            history_topics: 10000,
+            id_policy: IdPolicy::Publisher,
+            topic_rules: TopicRules::default(),
+            no_subscribers: NoSubscribers::Accept,
+            max_body_size: 65536,
+            binary: BinaryPolicy::Reject,
+            queue_limits: QueueLimits::default(),
+            heartbeat: Some(Duration::from_secs(30)),
+            greeting: Greeting::default(),
+            topic_greetings: Vec::new()
+        }
+    }
+}
+
+impl Options {
+    /// The greeting for subscribers of the topic or pattern with the given ID
+    pub fn greeting_for(&self, id: &str) -> &Greeting {
+        match self.topic_greetings.iter().find(|&&(ref glob, _)| pattern::glob_matches(glob, id)) {
+            Some(&(_, ref greeting)) => greeting,
+            None => &self.greeting
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PublishError {
+    IdNotAllowed
+}
+
+impl PublishError {
+    pub fn description(&self) -> &'static str {
+        match *self {
+            PublishError::IdNotAllowed => "Message must not contain an id field"
+        }
+    }
+}
+
+/// What became of a published message. `subscribers` counts the clients
+/// the message was queued for; `failed` counts those among them that could
+/// not be woken up, e.g. because their connection is closing.
+#[derive(RustcEncodable, Debug)]
+pub struct Receipt {
+    pub id: Option<String>,
+    pub topic: String,
+    pub subscribers: usize,
+    pub failed: usize
+}
+
+impl Receipt {
+    pub fn to_json(&self) -> EncodeResult<String> {
+        json::encode(self)
     }
 }
 
 #[derive(RustcEncodable)]
 pub struct Stats {
     pub clients: usize,
-    pub topics: usize
+    pub topics: usize,
+    pub patterns: usize,
+    /// Messages dropped from client queues that were full
+    pub dropped: usize,
+    /// Clients disconnected because their queue was full
+    pub disconnected: usize
+}
+
+/// Wakes the connection of a subscriber once messages are queued for it.
+/// Connections are woken through their `hyper::Control`.
+pub trait Notify: Send {
+    /// Returns false when the connection could not be woken
+    fn notify(&self, next: Next) -> bool;
 }
 
+impl Notify for Control {
+    fn notify(&self, next: Next) -> bool {
+        self.ready(next).is_ok()
+    }
+}
+
+// A client subscribed to a topic or pattern. The queue is shared with the
+// Manager's index of clients so publishing never has to look it up. Notify
+// is only Send; locking it lets concurrent publishes share the patterns.
+struct Subscriber {
+    client: Client,
+    notify: Mutex<Box<Notify>>,
+    queue: Arc<Mutex<Queue>>,
+    tagged: bool
+}
+
+// Reverse index of everything a client is subscribed to
+struct Subscription {
+    queue: Arc<Mutex<Queue>>,
+    topics: Vec<Topic>,
+    pattern: Option<Pattern>
+}
+
+impl Subscription {
+    fn is_empty(&self) -> bool {
+        self.topics.is_empty() && self.pattern.is_none()
+    }
+}
+
+// Tally of delivering messages to the clients of a topic or pattern
+#[derive(Default)]
+struct Delivery {
+    queued: usize,
+    failed: usize,
+    dropped: usize,
+    disconnected: usize
+}
+
+// Poisoned locks only mean another thread panicked while holding them;
+// the data itself remains usable.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Queues the messages for every subscriber in the list and wakes them up.
+// Subscribers of several topics receive the tagged messages.
+fn deliver(list: &HashMap<Client, Subscriber>, messages: &[Message], tagged: &[Message], limits: &QueueLimits, delivery: &mut Delivery) -> () {
+    for subscriber in list.values() {
+        let msgs = match subscriber.tagged {
+            true => tagged,
+            false => messages
+        };
+
+        let mut queued = false;
+        let mut overflow = false;
+
+        {
+            let mut queue = lock(&subscriber.queue);
+
+            // Clients disconnected for overflowing are skipped until removed
+            if queue.is_closed() {
+                continue;
+            }
+
+            // Add messages to client's queue; clones share the frame
+            for (index, msg) in msgs.iter().enumerate() {
+                match queue.push(msg.clone(), limits) {
+                    Push::Queued(dropped) => {
+                        queued = true;
+                        delivery.dropped += dropped;
+                    }
+
+                    Push::Dropped => delivery.dropped += 1,
+
+                    Push::Overflow => {
+                        overflow = true;
+                        delivery.dropped += queue.len() + msgs.len() - index;
+
+                        queue.close();
+
+                        break;
+                    }
+                }
+            }
+        }
+
+        if overflow {
+            info!("[Manager] Disconnecting client {:?} with a full queue", subscriber.client);
+
+            delivery.disconnected += 1;
+
+            if !lock(&subscriber.notify).notify(Next::end()) {
+                debug!("[Manager] Failed to signal client {:?} to disconnect", subscriber.client);
+            }
+
+            continue;
+        }
+
+        if !queued {
+            continue;
+        }
+
+        delivery.queued += 1;
+
+        // Signal Control to wakeup
+        match lock(&subscriber.notify).notify(Next::write()) {
+            true => debug!("[Manager] Publish signaled client {:?}", subscriber.client),
+            false => {
+                debug!("[Manager] Published failed to signal client {:?}", subscriber.client);
+
+                delivery.failed += 1;
+            }
+        }
+    }
+}
+
+// Topics are spread over several shards so that publishing to one topic
+// does not block publishing to, or subscribing to, the others
+const SHARDS: usize = 16;
+
+#[derive(Default)]
+struct Shard {
+    streams: HashMap<Topic, HashMap<Client, Subscriber>>,
+    histories: HashMap<Topic, History>,
+    sequences: HashMap<Topic, u64>,
+    // When each topic was last published to, in publishes to this shard, so
+    // the least recently used history and sequence can be evicted
+    published: HashMap<Topic, u64>,
+    clock: u64
+}
+
+impl Shard {
+    // Next ID in the topic's sequence; IDs start at 1 and only ever increase
+    fn next_id(&mut self, topic: &Topic) -> String {
+        let seq = self.sequences.entry(topic.clone()).or_insert(0);
+
+        *seq += 1;
+
+        seq.to_string()
+    }
+
+    // Records a publish to the topic. Once the shard already tracks `limit`
+    // other topics, the history and sequence of the least recently published
+    // one are forgotten first.
+    fn touch(&mut self, topic: &Topic, limit: usize) -> () {
+        if limit == 0 {
+            return;
+        }
+
+        if !self.published.contains_key(topic) && self.published.len() >= limit {
+            let oldest = self.published.iter()
+                .min_by_key(|&(_, &at)| at)
+                .map(|(id, _)| id.clone());
+
+            match oldest {
+                Some(oldest) => {
+                    self.histories.remove(&oldest);
+                    self.sequences.remove(&oldest);
+                    self.published.remove(&oldest);
+                }
+
+                None => ()
+            }
+        }
+
+        self.clock += 1;
+        self.published.insert(topic.clone(), self.clock);
+    }
+
+    fn history(&mut self, topic: &Topic, size: usize) -> &mut History {
+        self.histories.entry(topic.clone()).or_insert_with(|| History::new(size))
+    }
+
+    // Topics nobody is subscribed to anymore are forgotten; their history
+    // and sequence are kept for publishes and reconnecting clients
+    fn remove(&mut self, client: &Client, topic: &Topic) -> () {
+        let is_empty = match self.streams.get_mut(topic) {
+            Some(list) => {
+                list.remove(client);
+
+                list.is_empty()
+            }
+
+            None => false
+        };
+
+        if is_empty {
+            self.streams.remove(topic);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Patterns {
+    trie: PatternTrie,
+    streams: HashMap<Pattern, HashMap<Client, Subscriber>>
+}
+
+impl Patterns {
+    fn remove(&mut self, client: &Client, pattern: &Pattern) -> () {
+        let is_empty = match self.streams.get_mut(pattern) {
+            Some(list) => {
+                list.remove(client);
+
+                list.is_empty()
+            }
+
+            None => false
+        };
+
+        // Forget patterns nobody is subscribed to anymore
+        if is_empty {
+            self.streams.remove(pattern);
+            self.trie.remove(pattern);
+        }
+    }
+}
+
+/// Keeps track of subscribers and delivers published messages to them. The
+/// Manager is shared by every server thread and locks internally: topics
+/// are sharded, patterns have a lock of their own that publishes only read
+/// and each client's queue is locked separately so writing to one client
+/// never blocks publishing.
 pub struct Manager {
-    messages: HashMap<Client, Vec<Message>>,
-    streams: HashMap<Topic, Vec<(Client, Control)>>
+    options: Options,
+    clients: RwLock<HashMap<Client, Subscription>>,
+    shards: Vec<Mutex<Shard>>,
+    patterns: RwLock<Patterns>,
+    dropped: AtomicUsize,
+    disconnected: AtomicUsize,
+    closing: AtomicBool
 }
 
 impl Manager {
     pub fn new() -> Manager {
+        Manager::with_options(Options::default())
+    }
+
+    pub fn with_options(options: Options) -> Manager {
         Manager {
-            messages: HashMap::new(),
-            streams: HashMap::new()
+            options: options,
+            clients: RwLock::new(HashMap::new()),
+            shards: (0..SHARDS).map(|_| Mutex::new(Shard::default())).collect(),
+            patterns: RwLock::new(Patterns::default()),
+            dropped: AtomicUsize::new(0),
+            disconnected: AtomicUsize::new(0),
+            closing: AtomicBool::new(false)
+        }
+    }
+
+    // Topics with history per shard, rounded up so the shards together
+    // retain at least `history_topics`
+    fn history_limit(&self) -> usize {
+        (self.options.history_topics + SHARDS - 1) / SHARDS
+    }
+
+    fn shard_index(&self, topic: &Topic) -> usize {
+        let mut hasher = DefaultHasher::new();
+
+        topic.hash(&mut hasher);
+
+        hasher.finish() as usize % SHARDS
+    }
+
+    fn shard(&self, topic: &Topic) -> MutexGuard<Shard> {
+        lock(&self.shards[self.shard_index(topic)])
+    }
+
+    // Creates the client's message queue holding the given messages and
+    // records what the client is subscribed to. The caller must not hold
+    // the clients lock; shards may be locked as clients are never locked
+    // before a shard.
+    fn add_client(&self, client: &Client, msgs: Vec<Message>, topics: Vec<Topic>, pattern: Option<Pattern>) -> Arc<Mutex<Queue>> {
+        let queue = Arc::new(Mutex::new(Queue::with_messages(msgs)));
+        let mut clients = self.clients.write().unwrap_or_else(|e| e.into_inner());
+
+        clients.insert(client.clone(), Subscription {
+            queue: queue.clone(),
+            topics: topics,
+            pattern: pattern
+        });
+
+        queue
+    }
+
+    // Updates the client's subscription, dropping it once it is empty
+    fn update_client<F: FnOnce(&mut Subscription)>(&self, client: &Client, update: F) -> () {
+        let mut clients = self.clients.write().unwrap_or_else(|e| e.into_inner());
+
+        let is_empty = match clients.get_mut(client) {
+            Some(subscription) => {
+                update(subscription);
+
+                subscription.is_empty()
+            }
+
+            None => false
+        };
+
+        if is_empty {
+            clients.remove(client);
         }
     }
 
-    pub fn subscribe(&mut self, client: Client, topic: Topic, ctrl: Control) -> () {
+    /// Subscribes the client to the topic. When a `last_event_id` is given,
+    /// all retained messages published after it are queued for the client
+    /// and the number of queued messages is returned.
+    pub fn subscribe<N: Notify + 'static>(&self, client: Client, topic: Topic, ctrl: N, last_event_id: Option<String>) -> usize {
         info!("[Manager] Subscribe client {:?} to topic {:?}", client, topic);
 
-        // Create client's message queue
-        self.messages.insert(client.clone(), Vec::new());
+        // A client only ever has one subscription
+        self.unsubscribe_client(client.clone());
+
+        // The shard stays locked so nothing is published between replaying
+        // the history and subscribing
+        let mut shard = self.shard(&topic);
+
+        // Create client's message queue, replaying any missed messages
+        let replayed = match (last_event_id, shard.histories.get(&topic)) {
+            (Some(id), Some(history)) => {
+                debug!("[Manager] Replaying topic {:?} since {:?}", topic, id);
+
+                history.since(&id)
+            }
+
+            _ => Vec::new()
+        };
+
+        let queued = replayed.len();
+        let queue = self.add_client(&client, replayed, vec![topic.clone()], None);
 
         // Now "subscribe" the Client and Control stream to the Topic
-        self.streams.entry(topic).or_insert(Vec::new()).push((client, ctrl));
+        shard.streams.entry(topic).or_insert(HashMap::new()).insert(client.clone(), Subscriber {
+            client: client,
+            notify: Mutex::new(Box::new(ctrl)),
+            queue: queue,
+            tagged: false
+        });
+
+        queued
     }
 
-    pub fn unsubscribe(&mut self, client: Client, topic: Topic) -> () {
+    /// Subscribes the client to several topics at once. Messages are tagged
+    /// with the topic they were published to, see `Message::tagged`, and
+    /// missed messages are not replayed.
+    pub fn subscribe_all<N: Notify + Clone + 'static>(&self, client: Client, topics: &[Topic], ctrl: N) -> () {
+        info!("[Manager] Subscribe client {:?} to topics {:?}", client, topics);
+
+        self.unsubscribe_client(client.clone());
+
+        let mut unique: Vec<Topic> = Vec::with_capacity(topics.len());
+
+        for topic in topics {
+            if !unique.contains(topic) {
+                unique.push(topic.clone());
+            }
+        }
+
+        let queue = self.add_client(&client, Vec::new(), unique.clone(), None);
+
+        for topic in unique {
+            self.shard(&topic).streams.entry(topic).or_insert(HashMap::new()).insert(client.clone(), Subscriber {
+                client: client.clone(),
+                notify: Mutex::new(Box::new(ctrl.clone())),
+                queue: queue.clone(),
+                tagged: true
+            });
+        }
+    }
+
+    /// Subscribes the client to every topic matching the pattern. Event IDs
+    /// are not shared across topics, so missed messages are not replayed.
+    pub fn subscribe_pattern<N: Notify + 'static>(&self, client: Client, pattern: Pattern, ctrl: N) -> () {
+        info!("[Manager] Subscribe client {:?} to pattern {:?}", client, pattern);
+
+        self.unsubscribe_client(client.clone());
+
+        let queue = self.add_client(&client, Vec::new(), Vec::new(), Some(pattern.clone()));
+        let mut patterns = self.patterns.write().unwrap_or_else(|e| e.into_inner());
+
+        patterns.trie.insert(pattern.clone());
+        patterns.streams.entry(pattern).or_insert(HashMap::new()).insert(client.clone(), Subscriber {
+            client: client,
+            notify: Mutex::new(Box::new(ctrl)),
+            queue: queue,
+            tagged: true
+        });
+    }
+
+    pub fn unsubscribe_pattern(&self, client: Client, pattern: Pattern) -> () {
+        info!("[Manager] Unsubscribe client {:?} to pattern {:?}", client, pattern);
+
+        self.update_client(&client, |subscription| {
+            if subscription.pattern.as_ref() == Some(&pattern) {
+                subscription.pattern = None;
+            }
+        });
+
+        self.patterns.write().unwrap_or_else(|e| e.into_inner()).remove(&client, &pattern);
+    }
+
+    pub fn replay(&self, topic: &Topic, last_event_id: &str) -> Vec<Message> {
+        match self.shard(topic).histories.get(topic) {
+            Some(history) => {
+                debug!("[Manager] Replaying topic {:?} since {:?}", topic, last_event_id);
+
+                history.since(last_event_id)
+            }
+
+            None => Vec::new()
+        }
+    }
+
+    pub fn unsubscribe(&self, client: Client, topic: Topic) -> () {
         info!("[Manager] Unsubscribe client {:?} to topic {:?}", client, topic);
 
-        // Remove the message queue
-        self.messages.remove(&client.clone());
+        self.update_client(&client, |subscription| subscription.topics.retain(|subscribed| *subscribed != topic));
+
+        self.shard(&topic).remove(&client, &topic);
+    }
+
+    /// Queues a heartbeat comment for every subscriber that has not been
+    /// written to for the interval and wakes them. Clients that cannot be
+    /// woken anymore are unsubscribed. Returns the number of heartbeats.
+    pub fn heartbeat(&self, interval: Duration) -> usize {
+        let ping = Message::new(&b": ping".to_vec());
+        let mut sent = 0;
+        let mut dead = Vec::new();
 
-        // Remove the "subscribed" Client and Control tuple by index
-        match self.streams.get_mut(&topic) {
-            Some(mut list) => {
-                match list.binary_search_by(|tuple| tuple.0.cmp(&client)) {
-                    Ok(index) => {
-                        list.remove(index);
+        {
+            let mut beat = |list: &HashMap<Client, Subscriber>| {
+                for subscriber in list.values() {
+                    // Clients subscribed to several topics only get one heartbeat
+                    if !lock(&subscriber.queue).heartbeat(&ping, interval) {
+                        continue;
                     }
 
-                    Err(_) => ()
+                    match lock(&subscriber.notify).notify(Next::write()) {
+                        true => sent += 1,
+                        false => {
+                            debug!("[Manager] Heartbeat failed to signal client {:?}", subscriber.client);
+
+                            dead.push(subscriber.client.clone());
+                        }
+                    }
+                }
+            };
+
+            for shard in self.shards.iter() {
+                for list in lock(shard).streams.values() {
+                    beat(list);
                 }
             }
 
-            None => ()
+            for list in self.patterns.read().unwrap_or_else(|e| e.into_inner()).streams.values() {
+                beat(list);
+            }
+        }
+
+        for client in dead {
+            self.unsubscribe_client(client);
         }
+
+        sent
     }
 
-    pub fn publish(&mut self, topic: Topic, msg: &Vec<u8>) -> () {
-        info!("[Manager] Publish to topic {:?}", topic);
+    /// Queues the final message for every subscriber and wakes them. Nothing
+    /// is queued after the final message, so subscribers can be disconnected
+    /// once their queue is written. Returns the number of subscribers.
+    pub fn shutdown(&self, msg: &Message) -> usize {
+        info!("[Manager] Shutting down");
 
-        // Enumerate each client control tuple
-        match self.streams.get(&topic) {
-            Some(list) => {
-                for &(ref client, ref ctrl) in list {
-                    match self.messages.get_mut(&client.clone()) {
-                        Some(msgs) => {
-                            // Add message to client's queue
-                            msgs.push(Message::new(msg));
-
-                            // Signal Control to wakeup
-                            match ctrl.ready(Next::write()) {
-                                Ok(_) => debug!("[Manager] Publish signaled client {:?}", client),
-                                Err(_) => debug!("[Manager] Published failed to signal client {:?}", client)
-                            }
-                        }
+        self.closing.store(true, Ordering::SeqCst);
 
-                        None => info!("[Manager] Client {:?} has no messages Vec", client)
+        let mut notified = 0;
+
+        {
+            let mut notify = |list: &HashMap<Client, Subscriber>| {
+                for subscriber in list.values() {
+                    // Clients subscribed to several topics only get one final message
+                    if !lock(&subscriber.queue).push_final(msg) {
+                        continue;
+                    }
+
+                    match lock(&subscriber.notify).notify(Next::write()) {
+                        true => notified += 1,
+                        false => debug!("[Manager] Shutdown failed to signal client {:?}", subscriber.client)
                     }
                 }
+            };
+
+            for shard in self.shards.iter() {
+                for list in lock(shard).streams.values() {
+                    notify(list);
+                }
+            }
+
+            for list in self.patterns.read().unwrap_or_else(|e| e.into_inner()).streams.values() {
+                notify(list);
+            }
+        }
+
+        notified
+    }
+
+    /// Whether the Manager is shutting down; no new requests should be served
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// Removes every subscription of the client, e.g. once its connection
+    /// is closed
+    pub fn unsubscribe_client(&self, client: Client) -> () {
+        let subscription = self.clients.write().unwrap_or_else(|e| e.into_inner()).remove(&client);
+
+        match subscription {
+            Some(subscription) => {
+                info!("[Manager] Unsubscribe client {:?}", client);
+
+                for topic in subscription.topics.iter() {
+                    self.shard(topic).remove(&client, topic);
+                }
+
+                match subscription.pattern {
+                    Some(ref pattern) => self.patterns.write().unwrap_or_else(|e| e.into_inner()).remove(&client, pattern),
+                    None => ()
+                }
+            }
+
+            None => ()
+        }
+    }
+
+    pub fn publish(&self, topic: Topic, msg: &Vec<u8>) -> Result<Receipt, PublishError> {
+        let mut receipts = try!(self.publish_batch(topic, &[msg.clone()]));
+
+        Ok(receipts.remove(0))
+    }
+
+    /// Publishes several messages to the topic at once. Either every message
+    /// is published, in order, or none of them are.
+    pub fn publish_batch(&self, topic: Topic, msgs: &[Vec<u8>]) -> Result<Vec<Receipt>, PublishError> {
+        info!("[Manager] Publish {} message(s) to topic {:?}", msgs.len(), topic);
+
+        if self.options.id_policy == IdPolicy::Reject && msgs.iter().any(|msg| Message::has_id(msg)) {
+            info!("[Manager] Rejected message with id for topic {:?}", topic);
+
+            return Err(PublishError::IdNotAllowed);
+        }
+
+        // The shard stays locked until the messages are delivered so that
+        // subscribers receive them in the order their IDs were assigned
+        let mut shard = self.shard(&topic);
+        // Publishes only read the patterns, so those to other shards are not held up
+        let patterns = self.patterns.read().unwrap_or_else(|e| e.into_inner());
+
+        Ok(self.deliver_batch(&mut shard, &patterns, &topic, msgs))
+    }
+
+    // Assigns IDs to the messages, retains them and queues them for the
+    // subscribers of the topic and of matching patterns. The caller holds
+    // the topic's shard and the patterns.
+    fn deliver_batch(&self, shard: &mut Shard, patterns: &Patterns, topic: &Topic, msgs: &[Vec<u8>]) -> Vec<Receipt> {
+        let mut messages = Vec::with_capacity(msgs.len());
+
+        shard.touch(topic, self.history_limit());
+
+        for msg in msgs {
+            let message = match self.options.id_policy {
+                IdPolicy::Publisher => Message::new(msg),
+
+                IdPolicy::Override | IdPolicy::Reject => {
+                    let id = shard.next_id(topic);
+
+                    Message::with_id(msg, &id)
+                }
+            };
+
+            messages.push(message);
+        }
+
+        // Retain the messages for clients that reconnect later
+        if self.options.history_size > 0 {
+            let size = self.options.history_size;
+            let history = shard.history(topic, size);
+
+            for message in messages.iter() {
+                history.push(message.clone());
             }
+        }
+
+        let limits = &self.options.queue_limits;
+        let list = shard.streams.get(topic);
+        let matches = patterns.trie.matches(&topic.id);
 
+        // Only tag the messages when someone can receive them from several topics
+        let needs_tags = !matches.is_empty() || list.map_or(false, |list| list.values().any(|subscriber| subscriber.tagged));
+
+        let tagged: Vec<Message> = match needs_tags {
+            true => messages.iter().map(|message| message.tagged(&topic.id)).collect(),
+            false => Vec::new()
+        };
+
+        let mut delivery = Delivery::default();
+
+        // Enumerate each client control tuple
+        match list {
+            Some(list) => deliver(list, &messages, &tagged, limits, &mut delivery),
             None => ()
         }
+
+        // As well as those of clients subscribed to a matching pattern
+        for pattern in matches {
+            match patterns.streams.get(pattern) {
+                Some(list) => deliver(list, &tagged, &tagged, limits, &mut delivery),
+                None => ()
+            }
+        }
+
+        self.dropped.fetch_add(delivery.dropped, Ordering::Relaxed);
+        self.disconnected.fetch_add(delivery.disconnected, Ordering::Relaxed);
+
+        // Every message of a batch reaches the same subscribers
+        messages.iter().map(|message| {
+            Receipt {
+                id: message.id().map(|id| id.to_owned()),
+                topic: topic.id.to_string(),
+                subscribers: delivery.queued,
+                failed: delivery.failed
+            }
+        }).collect()
     }
 
-    pub fn messages_for(&mut self, client: Client) -> Vec<Message> {
+    /// Publishes the message to each of the topics. Either the message is
+    /// published to every topic or to none of them. The shards of all the
+    /// topics are locked at once, so no other message is published to any
+    /// of them in between.
+    pub fn publish_to(&self, topics: &[Topic], msg: &Vec<u8>) -> Result<Vec<Receipt>, PublishError> {
+        info!("[Manager] Publish message to topics {:?}", topics);
+
+        if self.options.id_policy == IdPolicy::Reject && Message::has_id(msg) {
+            info!("[Manager] Rejected message with id for topics {:?}", topics);
+
+            return Err(PublishError::IdNotAllowed);
+        }
+
+        let mut unique: Vec<&Topic> = Vec::with_capacity(topics.len());
+
+        // Publish only once to topics that are listed more than once
+        for topic in topics {
+            if !unique.contains(&topic) {
+                unique.push(topic);
+            }
+        }
+
+        let mut indexes: Vec<usize> = unique.iter().map(|topic| self.shard_index(topic)).collect();
+
+        indexes.sort();
+        indexes.dedup();
+
+        // Shards are locked in index order so that concurrent fan-outs
+        // cannot deadlock, then the patterns as in `publish_batch`
+        let mut shards: HashMap<usize, MutexGuard<Shard>> = HashMap::with_capacity(indexes.len());
+
+        for index in indexes {
+            shards.insert(index, lock(&self.shards[index]));
+        }
+
+        let patterns = self.patterns.read().unwrap_or_else(|e| e.into_inner());
+        let msgs = [msg.clone()];
+        let mut receipts = Vec::with_capacity(unique.len());
+
+        for topic in unique {
+            let shard = shards.get_mut(&self.shard_index(topic)).unwrap();
+
+            receipts.append(&mut self.deliver_batch(shard, &patterns, topic, &msgs));
+        }
+
+        Ok(receipts)
+    }
+
+    pub fn messages_for(&self, client: Client) -> Vec<Message> {
         info!("[Manager] Retrieving messages for {:?}", client);
 
-        match self.messages.get_mut(&client) {
-            Some (mut msgs) => {
-                let ret_msgs = msgs.split_off(0);
+        let queue = match self.clients.read().unwrap_or_else(|e| e.into_inner()).get(&client) {
+            Some(subscription) => subscription.queue.clone(),
+            None => return Vec::new()
+        };
 
-                msgs.clear();
+        let msgs = lock(&queue).take();
 
-                ret_msgs
+        msgs
+    }
+
+    /// Writes the messages queued for the client, see `Queue::write_to`
+    pub fn write_messages<W: FnMut(&[u8]) -> io::Result<usize>>(&self, client: Client, write: W) -> io::Result<Option<Vec<u8>>> {
+        let queue = match self.clients.read().unwrap_or_else(|e| e.into_inner()).get(&client) {
+            Some(subscription) => subscription.queue.clone(),
+            None => return Ok(None)
+        };
+
+        let written = lock(&queue).write_to(write);
+
+        written
+    }
+
+    /// Whether the client was sent its final message, see `shutdown`
+    pub fn is_finished(&self, client: Client) -> bool {
+        match self.clients.read().unwrap_or_else(|e| e.into_inner()).get(&client) {
+            Some(subscription) => {
+                let queue = lock(&subscription.queue);
+
+                queue.is_closed() && queue.is_empty()
             }
 
-            None => Vec::new()
+            None => false
         }
     }
 
     pub fn stats(&self) -> Stats {
         Stats {
-            clients: self.messages.len(),
-            topics: self.streams.len()
+            clients: self.clients.read().unwrap_or_else(|e| e.into_inner()).len(),
+            topics: self.shards.iter().map(|shard| lock(shard).streams.len()).sum(),
+            patterns: self.patterns.read().unwrap_or_else(|e| e.into_inner()).streams.len(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            disconnected: self.disconnected.load(Ordering::Relaxed)
         }
     }
 
@@ -189,22 +1157,124 @@ impl Manager {
 }
 
 pub struct Access {
-    has_pub_secret: bool,
-    has_sub_secret: bool,
-    pub_secret: Box<str>,
-    sub_secret: Box<str>
+    publisher: KeyStore,
+    subscriber: KeyStore,
+    leeway: i64,
+    cookie: Option<String>,
+    query_tokens: bool
+}
+
+// The credentials of an `Authorization: Bearer <token>` header
+fn bearer_token(header: &str) -> Option<String> {
+    let mut parts = header.trim().splitn(2, ' ');
+
+    match (parts.next(), parts.next().map(|token| token.trim())) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") && !token.is_empty() => Some(token.to_owned()),
+        _ => None
+    }
+}
+
+// The value of the named cookie in a `Cookie` header
+fn cookie_value(header: &str, name: &str) -> Option<String> {
+    for pair in header.split(';') {
+        let mut parts = pair.splitn(2, '=');
+
+        match (parts.next().map(|key| key.trim()), parts.next()) {
+            (Some(key), Some(value)) if key == name => return Some(value.trim().trim_matches('"').to_owned()),
+            _ => ()
+        }
+    }
+
+    None
 }
 
 impl Access {
-    fn new(pub_secret: String, sub_secret: String) -> Access {
+    /// Creates the access rules from the secrets; an empty secret disables
+    /// authentication for its role unless a key file is added
+    pub fn new(pub_secret: String, sub_secret: String) -> Access {
+        Access {
+            publisher: KeyStore::new(pub_secret),
+            subscriber: KeyStore::new(sub_secret),
+            leeway: 0,
+            cookie: None,
+            query_tokens: true
+        }
+    }
+
+    /// Reads tokens from the named cookie as well, for same-origin
+    /// `EventSource` connections which cannot set headers
+    pub fn with_token_cookie(self, name: String) -> Access {
+        Access {
+            cookie: Some(name),
+            ..self
+        }
+    }
+
+    /// Ignores tokens given in the query string, where they end up in the
+    /// logs of proxies along the way
+    pub fn without_query_tokens(self) -> Access {
+        Access {
+            query_tokens: false,
+            ..self
+        }
+    }
+
+    /// Finds the token of a request in the enabled sources, preferring the
+    /// `Authorization: Bearer` header over the cookie over the query string
+    pub fn find_token(&self, authorization: Option<&str>, cookies: Option<&str>, query: Option<String>) -> Option<String> {
+        let cookie = match (self.cookie.as_ref(), cookies) {
+            (Some(name), Some(cookies)) => cookie_value(cookies, name),
+            _ => None
+        };
+
+        let query = match self.query_tokens {
+            true => query,
+            false => None
+        };
+
+        authorization.and_then(bearer_token).or(cookie).or(query)
+    }
+
+    /// Allows the time claims of tokens to be off by the given seconds
+    pub fn with_leeway(self, leeway: u64) -> Access {
         Access {
-            has_pub_secret: pub_secret.len() > 0,
-            has_sub_secret: sub_secret.len() > 0,
-            pub_secret: pub_secret.into_boxed_str(),
-            sub_secret: sub_secret.into_boxed_str()
+            leeway: leeway as i64,
+            ..self
+        }
+    }
+
+    /// Verifies tokens for the role with the public keys of a PEM or JWKS
+    /// file as well, which enables authentication for the role
+    pub fn with_key_file(self, role: Role, path: PathBuf) -> Result<Access, KeyError> {
+        match role {
+            Role::Publish => Ok(Access { publisher: try!(self.publisher.with_file(path)), ..self }),
+            Role::Subscribe => Ok(Access { subscriber: try!(self.subscriber.with_file(path)), ..self })
+        }
+    }
+
+    fn keys(&self, role: Role) -> &KeyStore {
+        match role {
+            Role::Publish => &self.publisher,
+            Role::Subscribe => &self.subscriber
         }
     }
 
+    pub fn has_key_files(&self) -> bool {
+        self.publisher.has_file() || self.subscriber.has_file()
+    }
+
+    /// Reloads the key files, keeping the current keys of a role whose file
+    /// cannot be loaded
+    pub fn reload(&self) -> Result<(), KeyError> {
+        let publisher = self.publisher.reload();
+        let subscriber = self.subscriber.reload();
+
+        try!(publisher);
+        try!(subscriber);
+
+        Ok(())
+    }
+
     pub fn from_env() -> Access {
         let pub_secret = match env::var("ESPER_PUBLISHER_SECRET") {
             Ok(secret) => secret,
@@ -227,29 +1297,50 @@ impl Access {
         Access::new(pub_secret, sub_secret)
     }
 
-    pub fn is_authenticated_for_publish(&self, topic_id: Box<str>, token: Option<String>) -> bool {
-        match self.has_pub_secret {
+    fn authorize(&self, role: Role, topic_ids: &[&str], token: Option<String>) -> Result<(), AuthError> {
+        let keys = self.keys(role);
+
+        let validation = Validation {
+            role: Some(role),
+            leeway: self.leeway
+        };
+
+        match keys.is_enabled() {
             true => {
                 match token {
-                    Some(t) => authenticate(&*topic_id, t.as_str(), &*self.pub_secret),
-                    None => false // No token found but auth required
+                    Some(t) => authorize_with(topic_ids, t.as_str(), &keys.keys(), &validation),
+                    None => Err(AuthError::MissingToken) // No token found but auth required
                 }
             }
 
-            false => true // No published auth required -- return true
+            false => Ok(()) // No auth required for the role -- return Ok
         }
     }
 
-    pub fn is_authenticated_for_subscribe(&self, topic_id: Box<str>, token: Option<String>) -> bool {
-        match self.has_sub_secret {
-            true => {
-                match token {
-                    Some(t) => authenticate(&*topic_id, t.as_str(), &*self.sub_secret),
-                    None => false // No token found but auth required
-                }
-            }
+    /// Checks the token grants publishing to every one of the topics. The
+    /// IDs are those of parsed topics, so claims are compared to normalized IDs
+    pub fn authorize_publish(&self, topic_ids: &[&str], token: Option<String>) -> Result<(), AuthError> {
+        self.authorize(Role::Publish, topic_ids, token)
+    }
 
-            false => true // No subscribe auth required -- return true
-        }
+    /// Checks the token grants subscribing to every one of the topics, see
+    /// `authorize_publish`
+    pub fn authorize_subscribe(&self, topic_ids: &[&str], token: Option<String>) -> Result<(), AuthError> {
+        self.authorize(Role::Subscribe, topic_ids, token)
+    }
+
+    /// Checks the token may read the stats. The stats are meant for
+    /// developers and are not about any one topic, so any valid publisher
+    /// token is accepted, whatever topics it grants.
+    pub fn authorize_stats(&self, token: Option<String>) -> Result<(), AuthError> {
+        self.authorize(Role::Publish, &[], token)
+    }
+
+    pub fn is_authenticated_for_publish(&self, topic_id: Box<str>, token: Option<String>) -> bool {
+        self.authorize_publish(&[&*topic_id], token).is_ok()
+    }
+
+    pub fn is_authenticated_for_subscribe(&self, topic_id: Box<str>, token: Option<String>) -> bool {
+        self.authorize_subscribe(&[&*topic_id], token).is_ok()
     }
 }
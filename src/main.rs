@@ -1,6 +1,7 @@
 extern crate rustc_serialize;
 extern crate docopt;
 extern crate hyper;
+extern crate libc;
 
 #[macro_use]
 extern crate log;
@@ -10,20 +11,27 @@ use docopt::Docopt;
 
 extern crate esper;
 
-use std::thread;
-use std::sync::{Arc, Mutex};
+use std::{mem, ptr, thread};
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::path::PathBuf;
 use std::error::Error;
 
+use libc::c_int;
+
 use hyper::net::{HttpListener};
 use hyper::server::{Server};
 
-use esper::{Access, Manager};
+use esper::{Access, Greeting, Manager, Options, Pattern, QueueLimits, Topic, TopicRules};
+use esper::auth::Role;
+use esper::message::Event;
 use esper::handler::EventStream;
 
 const USAGE: &'static str = "esper - Event Source HTTP server, powered by hyper.
 
 Usage:
-  esper [--bind=<bind>] [--port=<port>] [--threads=<st>]
+  esper [options] [--topic-greeting=<spec>...]
   esper (-h | --help)
   esper (-v | --version)
 
@@ -33,6 +41,33 @@ Options:
   -b --bind=<bind>   Bind to specific IP [default: 127.0.0.1]
   -p --port=<port>   Run on a specific port number [default: 3000]
   -t --threads=<st>  Number of server threads [default: 2].
+  --history=<size>   Number of messages retained per topic for replay [default: 100].
+  --history-topics=<n>  Number of topics whose messages are retained, 0 for no limit [default: 10000].
+  --event-ids=<policy>  Assign event IDs to published messages; off, override or reject [default: off].
+  --topic-chars=<chars>  Characters allowed in topic IDs besides letters and digits.
+  --topic-min=<len>  Minimum length of topic IDs [default: 8].
+  --topic-max=<len>  Maximum length of topic IDs [default: 64].
+  --topic-regex=<re>    Regular expression topic IDs have to match.
+  --topic-case-sensitive  Keep the case of topic IDs instead of lowercasing them.
+  --no-subscribers=<status>  Publish status for topics without subscribers; accept, 404 or 410 [default: accept].
+  --max-body=<bytes>  Maximum size of publish request bodies [default: 65536].
+  --binary=<policy>  Handle non UTF-8 publish bodies; reject or base64 [default: reject].
+  --queue-size=<n>   Maximum number of messages queued per client, 0 for no limit [default: 1000].
+  --queue-bytes=<n>  Maximum bytes queued per client, 0 for no limit [default: 1048576].
+  --overflow=<policy>  Handle full client queues; drop-oldest, drop-newest, disconnect or coalesce [default: drop-oldest].
+  --heartbeat=<secs>  Seconds of inactivity before subscribers receive a heartbeat, 0 to disable [default: 30].
+  --retry=<ms>       Reconnection interval sent to subscribers when they connect, 0 to leave it to the browser [default: 0].
+  --padding=<bytes>  Length of a comment sent to subscribers when they connect [default: 0].
+  --topic-greeting=<spec>  Retry and padding for a topic ID or pattern, as ID=MS[,BYTES].
+  --shutdown-event=<name>  Event sent to subscribers when shutting down [default: shutdown].
+  --shutdown-retry=<ms>  Reconnection interval sent with the shutdown event, 0 to leave it out [default: 0].
+  --shutdown-timeout=<secs>  Seconds to wait for subscribers to receive the shutdown event [default: 10].
+  --token-leeway=<secs>  Seconds token exp, nbf and iat claims may be off by [default: 0].
+  --publisher-keys=<file>  PEM or JWKS file with public keys publisher tokens may be signed with.
+  --subscriber-keys=<file>  PEM or JWKS file with public keys subscriber tokens may be signed with.
+  --keys-reload=<secs>  Seconds between reloads of the key files, 0 to disable [default: 60].
+  --token-cookie=<name>  Cookie to read tokens from besides the Authorization header.
+  --no-query-token   Ignore tokens given as the token query parameter.
 ";
 
 #[derive(Debug, RustcDecodable)]
@@ -40,6 +75,33 @@ struct Args {
     flag_bind: String,
     flag_port: u32,
     flag_threads: u8,
+    flag_history: usize,
+    flag_history_topics: usize,
+    flag_event_ids: String,
+    flag_topic_chars: String,
+    flag_topic_min: usize,
+    flag_topic_max: usize,
+    flag_topic_regex: String,
+    flag_topic_case_sensitive: bool,
+    flag_no_subscribers: String,
+    flag_max_body: usize,
+    flag_binary: String,
+    flag_queue_size: usize,
+    flag_queue_bytes: usize,
+    flag_overflow: String,
+    flag_heartbeat: u64,
+    flag_retry: u64,
+    flag_padding: usize,
+    flag_topic_greeting: Vec<String>,
+    flag_shutdown_event: String,
+    flag_shutdown_retry: u64,
+    flag_shutdown_timeout: u64,
+    flag_token_leeway: u64,
+    flag_publisher_keys: String,
+    flag_subscriber_keys: String,
+    flag_keys_reload: u64,
+    flag_token_cookie: String,
+    flag_no_query_token: bool,
     flag_version: bool,
     flag_help: bool
 }
@@ -50,6 +112,131 @@ fn abort(message: &'static str) -> () {
     std::process::exit(0);
 }
 
+fn fail(message: String) -> ! {
+    println!("{}", message);
+    std::process::exit(1);
+}
+
+fn topic_rules(args: &Args) -> TopicRules {
+    // These characters separate topic IDs or act as wildcards in subscriptions
+    if args.flag_topic_chars.contains(',') || args.flag_topic_chars.contains('*') {
+        fail("Topic IDs cannot contain ',' or '*'".to_owned());
+    }
+
+    if args.flag_topic_min < 1 || args.flag_topic_min > args.flag_topic_max {
+        fail(format!("Invalid topic ID length range {}..{}", args.flag_topic_min, args.flag_topic_max));
+    }
+
+    let regex = match args.flag_topic_regex.is_empty() {
+        true => None,
+        false => Some(TopicRules::full_match(&args.flag_topic_regex).unwrap_or_else(|e| {
+            fail(format!("Invalid topic regex; {}", e))
+        }))
+    };
+
+    TopicRules {
+        extra_chars: args.flag_topic_chars.clone(),
+        min_len: args.flag_topic_min,
+        max_len: args.flag_topic_max,
+        case_sensitive: args.flag_topic_case_sensitive,
+        regex: regex
+    }
+}
+
+fn retry(ms: u64) -> Option<u64> {
+    match ms {
+        0 => None,
+        ms => Some(ms)
+    }
+}
+
+// Parses greetings given as ID=MS[,BYTES] where the ID may be a pattern
+fn topic_greeting(spec: &str, rules: &TopicRules) -> (String, Greeting) {
+    let (id, value) = match spec.rfind('=') {
+        Some(pos) => (&spec[..pos], &spec[pos + 1..]),
+        None => fail(format!("Invalid topic greeting {:?}; expected ID=MS[,BYTES]", spec))
+    };
+
+    let id = match id.contains('*') {
+        true => Pattern::parse(id, rules).map(|pattern| pattern.id().to_owned()),
+        false => Topic::parse(id, rules).map(|topic| topic.id().to_owned())
+    };
+
+    let id = id.unwrap_or_else(|e| fail(format!("Invalid topic greeting {:?}; {}", spec, e.description())));
+
+    let mut values = value.splitn(2, ',').map(|n| n.parse().unwrap_or_else(|_| {
+        fail(format!("Invalid topic greeting {:?}; expected ID=MS[,BYTES]", spec))
+    }));
+
+    let greeting = Greeting {
+        retry: values.next().and_then(|ms| retry(ms as u64)),
+        padding: values.next().unwrap_or(0)
+    };
+
+    (id, greeting)
+}
+
+// Blocks the signals for the calling thread and every thread it spawns
+// afterwards, so that they are only ever received through `wait_for_signal`
+fn block_signals(signals: &[c_int]) -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+
+        libc::sigemptyset(&mut set);
+
+        for &signal in signals {
+            libc::sigaddset(&mut set, signal);
+        }
+
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut());
+
+        set
+    }
+}
+
+fn wait_for_signal(set: &libc::sigset_t) -> c_int {
+    let mut signal = 0;
+
+    unsafe {
+        libc::sigwait(set, &mut signal);
+    }
+
+    signal
+}
+
+fn access(args: &Args) -> Access {
+    let mut access = Access::from_env().with_leeway(args.flag_token_leeway);
+
+    if !args.flag_token_cookie.is_empty() {
+        access = access.with_token_cookie(args.flag_token_cookie.clone());
+    }
+
+    if args.flag_no_query_token {
+        access = access.without_query_tokens();
+    }
+
+    for &(role, ref path) in [(Role::Publish, &args.flag_publisher_keys), (Role::Subscribe, &args.flag_subscriber_keys)].iter() {
+        if !path.is_empty() {
+            access = access.with_key_file(role, PathBuf::from(path)).unwrap_or_else(|e| {
+                fail(format!("Failed to load {:?} keys; {}", role, e.description()))
+            });
+        }
+    }
+
+    access
+}
+
+fn shutdown_message(args: &Args) -> esper::Message {
+    let event = Event {
+        event: Some(args.flag_shutdown_event.clone()),
+        data: Some(String::new()),
+        id: None,
+        retry: retry(args.flag_shutdown_retry)
+    };
+
+    event.to_message()
+}
+
 fn main() {
     println!("Welcome to esper -- the Event Source HTTP server, powered by hyper!\n");
     env_logger::init().unwrap_or_else(|_| abort("Failed to initialize logger!"));
@@ -64,29 +251,121 @@ fn main() {
         abort("esper v0.1.0");
     }
 
+    let rules = topic_rules(&args);
+    let topic_greetings = args.flag_topic_greeting.iter().map(|spec| topic_greeting(spec, &rules)).collect();
+
+    let options = Options {
+        history_size: args.flag_history,
+        history_topics: args.flag_history_topics,
+        id_policy: args.flag_event_ids.parse().unwrap_or_else(|e| fail(e)),
+        topic_rules: rules,
+        no_subscribers: args.flag_no_subscribers.parse().unwrap_or_else(|e| fail(e)),
+        max_body_size: args.flag_max_body,
+        binary: args.flag_binary.parse().unwrap_or_else(|e| fail(e)),
+        queue_limits: QueueLimits {
+            max_messages: args.flag_queue_size,
+            max_bytes: args.flag_queue_bytes,
+            policy: args.flag_overflow.parse().unwrap_or_else(|e| fail(e))
+        },
+        heartbeat: match args.flag_heartbeat {
+            0 => None,
+            secs => Some(Duration::from_secs(secs))
+        },
+        greeting: Greeting {
+            retry: retry(args.flag_retry),
+            padding: args.flag_padding
+        },
+        topic_greetings: topic_greetings
+    };
+
+    if args.flag_shutdown_event.is_empty() || args.flag_shutdown_event.contains(|c| c == '\n' || c == '\r') {
+        fail(format!("Invalid shutdown event name {:?}", args.flag_shutdown_event));
+    }
+
+    // Blocked before any thread is spawned so no thread is interrupted
+    let signals = block_signals(&[libc::SIGINT, libc::SIGTERM, libc::SIGHUP]);
+
     match format!("{}:{}", args.flag_bind, args.flag_port).parse() {
         Ok(addr) => {
             match HttpListener::bind(&addr) {
                 Ok(http_listener) => {
                     let mut handles = Vec::new();
+                    let (listening_tx, listening_rx) = channel();
 
-                    let mgr_ref = Arc::new(Mutex::new(Manager::new()));
-                    let acc_ref = Arc::new(Access::from_env());
+                    let mgr_ref = Arc::new(Manager::with_options(options.clone()));
+                    let opt_ref = Arc::new(options);
+                    let acc_ref = Arc::new(access(&args));
+
+                    if let Some(interval) = opt_ref.heartbeat {
+                        let mgr_inner = mgr_ref.clone();
+
+                        // Checking twice per interval keeps streams from idling much longer
+                        thread::spawn(move || {
+                            loop {
+                                thread::sleep(interval / 2);
+
+                                let sent = mgr_inner.heartbeat(interval);
+
+                                debug!("Sent {} heartbeat(s)", sent);
+                            }
+                        });
+                    }
+
+                    if acc_ref.has_key_files() && args.flag_keys_reload > 0 {
+                        let acc_inner = acc_ref.clone();
+                        let interval = Duration::from_secs(args.flag_keys_reload);
+
+                        // Rotated keys are picked up without a restart
+                        thread::spawn(move || {
+                            loop {
+                                thread::sleep(interval);
+
+                                match acc_inner.reload() {
+                                    Ok(()) => debug!("Reloaded key files"),
+                                    Err(e) => warn!("Failed to reload key files; {}", e.description())
+                                }
+                            }
+                        });
+                    }
 
                     for _ in 0..args.flag_threads {
                         match http_listener.try_clone() {
                             Ok(thread_listener) => {
+                                let opt_inner = opt_ref.clone();
                                 let acc_inner = acc_ref.clone();
                                 let mgr_inner = mgr_ref.clone();
+                                let listening_inner = listening_tx.clone();
 
                                 handles.push(thread::spawn(move || {
                                     let server = Server::new(thread_listener).handle(|ctrl| {
-                                        EventStream::new(ctrl, acc_inner.clone(), mgr_inner.clone())
+                                        EventStream::new(ctrl, opt_inner.clone(), acc_inner.clone(), mgr_inner.clone())
                                     });
 
-                                    match server {
-                                        Ok(_) => debug!("Started server thread"),
-                                        Err(err) => warn!("Failed to start server thread; {:?}", err.description())
+                                    // Every thread reports back once, so the main thread
+                                    // is not left waiting for servers that never started
+                                    let server_loop = match server {
+                                        Ok((listening, server_loop)) => {
+                                            debug!("Started server thread");
+
+                                            // The main thread closes the server on shutdown
+                                            listening_inner.send(Some(listening)).unwrap_or_else(|_| warn!("Failed to hand over server"));
+
+                                            Some(server_loop)
+                                        }
+
+                                        Err(err) => {
+                                            warn!("Failed to start server thread; {:?}", err.description());
+
+                                            listening_inner.send(None).unwrap_or_else(|_| warn!("Failed to hand over server"));
+
+                                            None
+                                        }
+                                    };
+
+                                    drop(listening_inner);
+
+                                    if let Some(server_loop) = server_loop {
+                                        server_loop.run();
                                     }
                                 }));
                             }
@@ -97,6 +376,43 @@ fn main() {
                         }
                     }
 
+                    // Key files are reloaded on SIGHUP, any other signal shuts down
+                    let signal = loop {
+                        match wait_for_signal(&signals) {
+                            libc::SIGHUP => match acc_ref.reload() {
+                                Ok(()) => info!("Reloaded key files"),
+                                Err(e) => warn!("Failed to reload key files; {}", e.description())
+                            },
+
+                            signal => break signal
+                        }
+                    };
+
+                    info!("Received signal {}; shutting down", signal);
+
+                    // Refuse new requests and send every subscriber the final event
+                    let notified = mgr_ref.shutdown(&shutdown_message(&args));
+                    let deadline = Instant::now() + Duration::from_secs(args.flag_shutdown_timeout);
+
+                    info!("Notified {} subscriber(s) of the shutdown", notified);
+
+                    // Subscribers are removed as soon as the final event is written to them
+                    while mgr_ref.stats().clients > 0 && Instant::now() < deadline {
+                        thread::sleep(Duration::from_millis(100));
+                    }
+
+                    match mgr_ref.stats().clients {
+                        0 => info!("All subscribers received the shutdown event"),
+                        clients => warn!("Shutting down with {} subscriber(s) left", clients)
+                    }
+
+                    // Ends once every thread reported back, even if one died first
+                    drop(listening_tx);
+
+                    for listening in listening_rx.iter().flatten() {
+                        listening.close();
+                    }
+
                     for handle in handles {
                         handle.join().unwrap();
                     }
@@ -0,0 +1,390 @@
+use std::sync::Arc;
+
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use rustc_serialize::json::Json;
+
+/// A published message, delimited and ready to be written to subscribers.
+/// Messages are queued for every subscriber of a topic, so clones share
+/// the underlying frame rather than copying it.
+#[derive(Clone)]
+pub struct Message {
+    frame: Arc<Frame>
+}
+
+struct Frame {
+    body: Vec<u8>,
+    id: Option<Box<str>>,
+    event: Option<Box<str>>
+}
+
+impl Message {
+    pub fn new(buf: &Vec<u8>) -> Message {
+        // Append Event Source delimiter ("\n\n")
+        let mut delimiter = vec![10 as u8, 10 as u8];
+        let mut delimited_body = buf.clone();
+
+        delimited_body.append(&mut delimiter);
+
+        Message {
+            frame: Arc::new(Frame {
+                id: Message::find_id(buf),
+                event: Message::find_event(buf),
+                body: delimited_body
+            })
+        }
+    }
+
+    /// Builds a message carrying the given ID. Any "id" fields already
+    /// present in the buffer are dropped.
+    pub fn with_id(buf: &Vec<u8>, id: &str) -> Message {
+        let text = String::from_utf8_lossy(buf);
+        let mut stamped = format!("id: {}", id);
+
+        for line in text.lines().filter(|line| !Message::is_id_line(line)) {
+            stamped.push('\n');
+            stamped.push_str(line);
+        }
+
+        Message::new(&stamped.into_bytes())
+    }
+
+    /// Tags the message with the topic it was published to by prefixing its
+    /// event type, e.g. `event: abcdef123` for unnamed events and
+    /// `event: abcdef123:update` for events named `update`.
+    pub fn tagged(&self, topic_id: &str) -> Message {
+        let text = String::from_utf8_lossy(&self.frame.body);
+        let lines: Vec<&str> = text.trim_right_matches('\n').split('\n').collect();
+
+        let event = lines.iter().filter(|line| Message::is_event_line(line)).last().map(|line| {
+            let value = if line.len() > 6 { &line[6..] } else { "" };
+
+            if value.starts_with(' ') { &value[1..] } else { value }
+        });
+
+        let mut tagged = match event {
+            Some(event) if !event.is_empty() => format!("event: {}:{}", topic_id, event),
+            _ => format!("event: {}", topic_id)
+        };
+
+        for line in lines.iter().filter(|line| !Message::is_event_line(line)) {
+            tagged.push('\n');
+            tagged.push_str(line);
+        }
+
+        Message::new(&tagged.into_bytes())
+    }
+
+    fn is_event_line(line: &str) -> bool {
+        line == "event" || line.starts_with("event:")
+    }
+
+    pub fn has_id(buf: &Vec<u8>) -> bool {
+        Message::find_id(buf).is_some()
+    }
+
+    fn is_id_line(line: &str) -> bool {
+        line == "id" || line.starts_with("id:")
+    }
+
+    // The last "id" field of the event wins, just like it does in the browser
+    fn find_id(buf: &Vec<u8>) -> Option<Box<str>> {
+        let text = String::from_utf8_lossy(buf);
+        let mut id = None;
+
+        for line in text.lines().filter(|line| Message::is_id_line(line)) {
+            let value = if line.len() > 3 { &line[3..] } else { "" };
+            let value = if value.starts_with(' ') { &value[1..] } else { value };
+
+            id = Some(value.to_owned().into_boxed_str());
+        }
+
+        id
+    }
+
+    fn find_event(buf: &Vec<u8>) -> Option<Box<str>> {
+        let text = String::from_utf8_lossy(buf);
+        let mut event = None;
+
+        for line in text.lines().filter(|line| Message::is_event_line(line)) {
+            let value = if line.len() > 6 { &line[6..] } else { "" };
+            let value = if value.starts_with(' ') { &value[1..] } else { value };
+
+            event = Some(value.to_owned().into_boxed_str());
+        }
+
+        event
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        match self.frame.id {
+            Some(ref id) => Some(&*id),
+            None => None
+        }
+    }
+
+    /// The event type of the message; `None` for unnamed events
+    pub fn event(&self) -> Option<&str> {
+        match self.frame.event {
+            Some(ref event) => Some(&*event),
+            None => None
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.frame.body.len()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        self.frame.body.as_slice()
+    }
+}
+
+/// A single event as submitted to the publish route
+#[derive(Debug, PartialEq)]
+pub struct Event {
+    pub event: Option<String>,
+    pub data: Option<String>,
+    pub id: Option<String>,
+    pub retry: Option<u64>
+}
+
+impl Event {
+    /// Parses an event from a JSON object. The `data` member is required;
+    /// string data is sent as is, any other JSON value is sent encoded.
+    pub fn from_json(buf: &[u8]) -> Result<Event, String> {
+        let text = match ::std::str::from_utf8(buf) {
+            Ok(text) => text,
+            Err(_) => return Err("JSON body must be valid UTF-8".to_owned())
+        };
+
+        match Json::from_str(text) {
+            Ok(json) => Event::from_json_value(&json),
+            Err(e) => Err(format!("Invalid JSON; {}", e))
+        }
+    }
+
+    /// Parses a batch of events from a JSON array of event objects
+    pub fn from_json_array(buf: &[u8]) -> Result<Vec<Event>, String> {
+        let text = match ::std::str::from_utf8(buf) {
+            Ok(text) => text,
+            Err(_) => return Err("JSON body must be valid UTF-8".to_owned())
+        };
+
+        match Json::from_str(text) {
+            Ok(Json::Array(values)) => {
+                let mut events = Vec::with_capacity(values.len());
+
+                for (index, value) in values.iter().enumerate() {
+                    match Event::from_json_value(value) {
+                        Ok(event) => events.push(event),
+                        Err(e) => return Err(format!("Event {}: {}", index, e))
+                    }
+                }
+
+                Event::non_empty(events)
+            }
+
+            Ok(_) => Err("Batch must be a JSON array".to_owned()),
+            Err(e) => Err(format!("Invalid JSON; {}", e))
+        }
+    }
+
+    /// Parses a batch of events from newline delimited JSON; blank lines
+    /// are skipped
+    pub fn from_ndjson(buf: &[u8]) -> Result<Vec<Event>, String> {
+        let text = match ::std::str::from_utf8(buf) {
+            Ok(text) => text,
+            Err(_) => return Err("JSON body must be valid UTF-8".to_owned())
+        };
+
+        let mut events = Vec::new();
+
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let event = match Json::from_str(line) {
+                Ok(json) => Event::from_json_value(&json),
+                Err(e) => Err(format!("Invalid JSON; {}", e))
+            };
+
+            match event {
+                Ok(event) => events.push(event),
+                Err(e) => return Err(format!("Event {}: {}", events.len(), e))
+            }
+        }
+
+        Event::non_empty(events)
+    }
+
+    fn non_empty(events: Vec<Event>) -> Result<Vec<Event>, String> {
+        match events.len() {
+            0 => Err("Batch does not contain any events".to_owned()),
+            _ => Ok(events)
+        }
+    }
+
+    pub fn from_json_value(json: &Json) -> Result<Event, String> {
+        let obj = match json.as_object() {
+            Some(obj) => obj,
+            None => return Err("Event must be a JSON object".to_owned())
+        };
+
+        let data = match obj.get("data") {
+            Some(&Json::String(ref data)) => Some(data.clone()),
+            Some(&Json::Null) | None => return Err("Event is missing data".to_owned()),
+            Some(data) => Some(data.to_string())
+        };
+
+        let event = match obj.get("event") {
+            Some(&Json::String(ref event)) => Some(try!(Event::single_line("event", event))),
+            Some(&Json::Null) | None => None,
+            Some(_) => return Err("Event field event must be a string".to_owned())
+        };
+
+        let id = match obj.get("id") {
+            Some(&Json::String(ref id)) => Some(try!(Event::single_line("id", id))),
+            Some(&Json::U64(id)) => Some(id.to_string()),
+            Some(&Json::Null) | None => None,
+            Some(_) => return Err("Event field id must be a string or a positive integer".to_owned())
+        };
+
+        let retry = match obj.get("retry") {
+            Some(&Json::U64(retry)) => Some(retry),
+            Some(&Json::Null) | None => None,
+            Some(_) => return Err("Event field retry must be a positive integer".to_owned())
+        };
+
+        Ok(Event {
+            event: event,
+            data: data,
+            id: id,
+            retry: retry
+        })
+    }
+
+    /// Wraps a binary payload, which cannot be sent as is over an Event
+    /// Source stream, into the base64 encoded data of an event
+    pub fn from_binary(buf: &[u8]) -> Event {
+        Event {
+            event: None,
+            data: Some(buf.to_base64(STANDARD)),
+            id: None,
+            retry: None
+        }
+    }
+
+    /// Parses an event from its Event Source representation. Line endings
+    /// may be LF, CRLF or CR. Comments are dropped and the body must hold
+    /// exactly one event made up of known fields.
+    pub fn parse(buf: &[u8]) -> Result<Event, String> {
+        let text = match ::std::str::from_utf8(buf) {
+            Ok(text) => text.replace("\r\n", "\n").replace('\r', "\n"),
+            Err(_) => return Err("Message must be valid UTF-8".to_owned())
+        };
+
+        let mut event = Event {
+            event: None,
+            data: None,
+            id: None,
+            retry: None
+        };
+
+        let mut has_field = false;
+        let text = text.trim_right_matches('\n');
+
+        if text.is_empty() {
+            return Err("Message does not contain any fields".to_owned());
+        }
+
+        for (num, line) in text.split('\n').enumerate() {
+            let (name, value) = match line.find(':') {
+                Some(0) => continue, // comment line
+                Some(pos) => {
+                    let value = &line[pos + 1..];
+
+                    (&line[..pos], if value.starts_with(' ') { &value[1..] } else { value })
+                }
+
+                None if line.is_empty() => {
+                    return Err(format!("Line {} is blank; a message must contain a single event", num + 1));
+                }
+
+                None => (line, "")
+            };
+
+            match name {
+                "event" => event.event = Some(value.to_owned()),
+                "id" => event.id = Some(try!(Event::single_line("id", value))),
+
+                "data" => {
+                    event.data = match event.data.take() {
+                        Some(data) => Some(format!("{}\n{}", data, value)),
+                        None => Some(value.to_owned())
+                    }
+                }
+
+                "retry" => {
+                    match value.parse::<u64>() {
+                        Ok(retry) if value.chars().all(|c| c.is_digit(10)) => event.retry = Some(retry),
+                        _ => return Err(format!("Line {} has an invalid retry value {:?}", num + 1, value))
+                    }
+                }
+
+                _ => return Err(format!("Line {} has an unknown field {:?}", num + 1, name))
+            }
+
+            has_field = true;
+        }
+
+        if has_field {
+            Ok(event)
+        } else {
+            Err("Message does not contain any fields".to_owned())
+        }
+    }
+
+    // Line breaks would end the field early and inject arbitrary fields
+    fn single_line(name: &str, value: &str) -> Result<String, String> {
+        if value.contains('\n') || value.contains('\r') {
+            Err(format!("Event field {} must not contain line breaks", name))
+        } else if name == "id" && value.contains('\0') {
+            Err("Event field id must not contain NULL characters".to_owned())
+        } else {
+            Ok(value.to_owned())
+        }
+    }
+
+    /// Serializes the event into its Event Source representation (without
+    /// the trailing delimiter). Each line of the data gets its own field so
+    /// multi-line payloads arrive intact.
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut frame = String::new();
+
+        if let Some(ref event) = self.event {
+            frame.push_str(&format!("event: {}\n", event));
+        }
+
+        if let Some(ref id) = self.id {
+            frame.push_str(&format!("id: {}\n", id));
+        }
+
+        if let Some(retry) = self.retry {
+            frame.push_str(&format!("retry: {}\n", retry));
+        }
+
+        if let Some(ref data) = self.data {
+            let data = data.replace("\r\n", "\n").replace('\r', "\n");
+
+            for line in data.split('\n') {
+                frame.push_str(&format!("data: {}\n", line));
+            }
+        }
+
+        // Drop the final line break; Message appends the delimiter
+        frame.pop();
+
+        frame.into_bytes()
+    }
+
+    pub fn to_message(&self) -> Message {
+        Message::new(&self.to_frame())
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use {TopicError, TopicRules};
+
+/// A topic pattern such as `org123*`. A `*` matches any run of characters,
+/// so a single trailing `*` subscribes to every topic with that prefix.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Pattern {
+    id: Box<str>
+}
+
+impl Pattern {
+    /// Patterns may use the characters allowed in topic IDs plus `*` and may
+    /// be shorter than topic IDs, but not longer
+    pub fn parse(id: &str, rules: &TopicRules) -> Result<Pattern, TopicError> {
+        let len = id.chars().count();
+
+        if !id.contains('*') {
+            return Err(TopicError::NotAPattern);
+        } else if len > rules.max_len {
+            return Err(TopicError::TooLong(rules.max_len));
+        }
+
+        match id.chars().find(|&c| !(c == '*' || rules.is_allowed(c))) {
+            Some(c) => Err(TopicError::InvalidCharacter(c)),
+
+            None => Ok(Pattern {
+                id: rules.normalize(id).into_boxed_str()
+            })
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &*self.id
+    }
+
+    /// The literal part of the pattern before its first wildcard
+    pub fn prefix(&self) -> &str {
+        match self.id.find('*') {
+            Some(pos) => &self.id[..pos],
+            None => &*self.id
+        }
+    }
+
+    pub fn matches(&self, topic_id: &str) -> bool {
+        glob_matches(&self.id, topic_id)
+    }
+}
+
+/// Matches the topic ID against a glob where `*` matches any run of
+/// characters. Without a `*` the glob only matches the very same ID.
+pub fn glob_matches(glob: &str, topic_id: &str) -> bool {
+    let pattern: Vec<char> = glob.chars().collect();
+    let topic: Vec<char> = topic_id.chars().collect();
+
+    // Greedy wildcard matching, backtracking to the last `*` seen
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < topic.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == topic[t] {
+            p += 1;
+            t += 1;
+        } else {
+            match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+
+                None => return false
+            }
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<char, Node>,
+    patterns: Vec<Pattern>
+}
+
+/// Patterns indexed by their literal prefix. Finding the patterns for a
+/// topic only visits the nodes along the topic ID, no matter how many
+/// patterns are stored.
+#[derive(Default)]
+pub struct PatternTrie {
+    root: Node,
+    len: usize
+}
+
+impl PatternTrie {
+    pub fn new() -> PatternTrie {
+        PatternTrie::default()
+    }
+
+    pub fn insert(&mut self, pattern: Pattern) -> () {
+        let mut node = &mut self.root;
+
+        for c in pattern.prefix().chars() {
+            node = node.children.entry(c).or_insert_with(Node::default);
+        }
+
+        if !node.patterns.contains(&pattern) {
+            node.patterns.push(pattern);
+            self.len += 1;
+        }
+    }
+
+    pub fn remove(&mut self, pattern: &Pattern) -> () {
+        let chars: Vec<char> = pattern.prefix().chars().collect();
+
+        if PatternTrie::remove_from(&mut self.root, &chars, pattern) {
+            self.len -= 1;
+        }
+    }
+
+    // Removes the pattern below the node and prunes nodes left empty
+    fn remove_from(node: &mut Node, chars: &[char], pattern: &Pattern) -> bool {
+        match chars.split_first() {
+            Some((c, rest)) => {
+                let (removed, prune) = match node.children.get_mut(c) {
+                    Some(child) => {
+                        let removed = PatternTrie::remove_from(child, rest, pattern);
+
+                        (removed, child.patterns.is_empty() && child.children.is_empty())
+                    }
+
+                    None => (false, false)
+                };
+
+                if prune {
+                    node.children.remove(c);
+                }
+
+                removed
+            }
+
+            None => {
+                let before = node.patterns.len();
+
+                node.patterns.retain(|p| p != pattern);
+
+                node.patterns.len() < before
+            }
+        }
+    }
+
+    /// Returns every stored pattern matching the topic ID
+    pub fn matches(&self, topic_id: &str) -> Vec<&Pattern> {
+        let mut found = Vec::new();
+        let mut node = &self.root;
+
+        found.extend(node.patterns.iter().filter(|p| p.matches(topic_id)));
+
+        for c in topic_id.chars() {
+            match node.children.get(&c) {
+                Some(child) => {
+                    node = child;
+
+                    found.extend(node.patterns.iter().filter(|p| p.matches(topic_id)));
+                }
+
+                None => break
+            }
+        }
+
+        found
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
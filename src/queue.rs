@@ -0,0 +1,254 @@
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use Message;
+
+/// What to do when a client's queue exceeds its limits, typically because
+/// the client reads slower than messages are published.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued messages to make room
+    DropOldest,
+    /// Drop the message being queued
+    DropNewest,
+    /// Disconnect the client and drop its queue
+    Disconnect,
+    /// Replace queued messages of the same named event type, then drop the
+    /// oldest
+    Coalesce
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OverflowPolicy, String> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" => Ok(OverflowPolicy::DropNewest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            "coalesce" => Ok(OverflowPolicy::Coalesce),
+            _ => Err(format!("Unknown overflow policy {:?}", s))
+        }
+    }
+}
+
+/// Limits of a client's queue. A limit of `0` disables it.
+#[derive(Clone, Debug)]
+pub struct QueueLimits {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+    pub policy: OverflowPolicy
+}
+
+impl Default for QueueLimits {
+    fn default() -> QueueLimits {
+        QueueLimits {
+            max_messages: 1000,
+            max_bytes: 1024 * 1024,
+            policy: OverflowPolicy::DropOldest
+        }
+    }
+}
+
+impl QueueLimits {
+    fn exceeded(&self, len: usize, bytes: usize) -> bool {
+        (self.max_messages > 0 && len > self.max_messages) || (self.max_bytes > 0 && bytes > self.max_bytes)
+    }
+}
+
+/// The outcome of queuing a message
+#[derive(Debug, PartialEq)]
+pub enum Push {
+    /// The message was queued after dropping the given number of messages
+    Queued(usize),
+    /// The message itself was dropped
+    Dropped,
+    /// The queue overflowed and the client has to be disconnected
+    Overflow
+}
+
+/// Messages waiting to be written to a client
+pub struct Queue {
+    messages: VecDeque<Message>,
+    bytes: usize,
+    closed: bool,
+    active_at: Instant
+}
+
+impl Queue {
+    pub fn new() -> Queue {
+        Queue {
+            messages: VecDeque::new(),
+            bytes: 0,
+            closed: false,
+            active_at: Instant::now()
+        }
+    }
+
+    /// Builds a queue holding the given messages regardless of any limits,
+    /// e.g. those replayed to a reconnecting client
+    pub fn with_messages(msgs: Vec<Message>) -> Queue {
+        let bytes = msgs.iter().map(|msg| msg.len()).sum();
+
+        Queue {
+            messages: msgs.into_iter().collect(),
+            bytes: bytes,
+            closed: false,
+            active_at: Instant::now()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn push(&mut self, msg: Message, limits: &QueueLimits) -> Push {
+        if self.closed {
+            return Push::Dropped;
+        }
+
+        // A single message larger than the byte limit cannot be queued at all,
+        // a client that has to receive every message cannot keep up then
+        if limits.exceeded(1, msg.len()) {
+            return match limits.policy {
+                OverflowPolicy::Disconnect => Push::Overflow,
+                _ => Push::Dropped
+            };
+        }
+
+        if !limits.exceeded(self.messages.len() + 1, self.bytes + msg.len()) {
+            self.bytes += msg.len();
+            self.messages.push_back(msg);
+
+            return Push::Queued(0);
+        }
+
+        match limits.policy {
+            OverflowPolicy::DropNewest => Push::Dropped,
+            OverflowPolicy::Disconnect => Push::Overflow,
+
+            OverflowPolicy::DropOldest | OverflowPolicy::Coalesce => {
+                let len = self.messages.len();
+
+                // Only the latest message of each named event type matters when
+                // coalescing, unnamed messages are dropped oldest first
+                match (limits.policy, msg.event()) {
+                    (OverflowPolicy::Coalesce, Some(event)) => {
+                        self.messages.retain(|queued| queued.event() != Some(event));
+                    }
+
+                    _ => ()
+                }
+
+                self.bytes = self.messages.iter().map(|queued| queued.len()).sum();
+                self.bytes += msg.len();
+                self.messages.push_back(msg);
+
+                while limits.exceeded(self.messages.len(), self.bytes) {
+                    match self.messages.pop_front() {
+                        Some(dropped) => self.bytes -= dropped.len(),
+                        None => break
+                    }
+                }
+
+                Push::Queued(len + 1 - self.messages.len())
+            }
+        }
+    }
+
+    /// Drops every queued message; nothing is queued for a closed queue
+    pub fn close(&mut self) -> () {
+        self.messages.clear();
+        self.bytes = 0;
+        self.closed = true;
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Queues the heartbeat when nothing was queued or written for the
+    /// given interval. Heartbeats are not subject to the queue limits.
+    pub fn heartbeat(&mut self, msg: &Message, interval: Duration) -> bool {
+        if self.closed || !self.messages.is_empty() || self.active_at.elapsed() < interval {
+            return false;
+        }
+
+        self.bytes += msg.len();
+        self.messages.push_back(msg.clone());
+        self.active_at = Instant::now();
+
+        true
+    }
+
+    /// Queues the last message the client is going to receive regardless of
+    /// the queue limits
+    pub fn push_final(&mut self, msg: &Message) -> bool {
+        if self.closed {
+            return false;
+        }
+
+        self.bytes += msg.len();
+        self.messages.push_back(msg.clone());
+        self.closed = true;
+
+        true
+    }
+
+    /// Writes queued messages until the queue is empty or the writer would
+    /// block. Messages leave the queue once they are written in full, so
+    /// those the writer could not take still count towards the limits.
+    /// Returns `None` once everything was written and otherwise what is
+    /// left of a partially written message, which may be nothing.
+    pub fn write_to<W: FnMut(&[u8]) -> io::Result<usize>>(&mut self, mut write: W) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let written = match self.messages.front() {
+                Some(msg) => match write(msg.as_slice()) {
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Some(Vec::new())),
+                    Err(e) => return Err(e)
+                },
+
+                None => return Ok(None)
+            };
+
+            if written == 0 {
+                return Ok(Some(Vec::new()));
+            }
+
+            self.active_at = Instant::now();
+
+            let msg = match self.messages.pop_front() {
+                Some(msg) => msg,
+                None => return Ok(None)
+            };
+
+            self.bytes -= msg.len();
+
+            if written < msg.len() {
+                return Ok(Some(msg.as_slice()[written..].to_vec()));
+            }
+        }
+    }
+
+    /// Takes every queued message, leaving the queue empty
+    pub fn take(&mut self) -> Vec<Message> {
+        if !self.messages.is_empty() {
+            self.active_at = Instant::now();
+        }
+
+        self.bytes = 0;
+
+        self.messages.drain(..).collect()
+    }
+}
@@ -0,0 +1,141 @@
+use hyper::{Get, Post, Method, StatusCode};
+use rustc_serialize::json::{self, EncodeResult};
+use url::percent_encoding::percent_decode;
+
+use auth::AuthError;
+use TopicError;
+
+/// The endpoints served by esper. Topic IDs are passed on undecoded as
+/// they may be a single ID, a comma separated list or a pattern.
+#[derive(Debug, PartialEq)]
+pub enum Endpoint {
+    Stats,
+    Subscribe(String),
+    Publish(String),
+    PublishBatch(String),
+    FanOut
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RouteError {
+    NotFound,
+    MethodNotAllowed(Method)
+}
+
+/// Splits the path into its resource and the rest of the path, which holds
+/// the percent-decoded topic ID. Topic IDs may contain slashes when allowed
+/// by the `TopicRules`, so only the first segment is split off.
+fn split_path(path: &str) -> (&str, Option<String>) {
+    let path = path.trim_left_matches('/');
+
+    match path.find('/') {
+        Some(pos) => {
+            let rest = percent_decode(path[pos + 1..].as_bytes()).decode_utf8_lossy().into_owned();
+
+            (&path[..pos], Some(rest))
+        }
+
+        None => (path, None)
+    }
+}
+
+/// Finds the endpoint for the request
+pub fn route(method: &Method, path: &str) -> Result<Endpoint, RouteError> {
+    let (resource, rest) = split_path(path);
+
+    let (endpoint, allowed) = match (resource, rest) {
+        ("stats", None) => (Endpoint::Stats, Get),
+
+        ("subscribe", None) => (Endpoint::Subscribe(String::new()), Get),
+        ("subscribe", Some(ids)) => (Endpoint::Subscribe(ids), Get),
+
+        ("publish", None) => (Endpoint::FanOut, Post),
+        ("publish", Some(ref id)) if id.ends_with("/batch") => {
+            (Endpoint::PublishBatch(id[..id.len() - 6].to_owned()), Post)
+        }
+
+        ("publish", Some(id)) => (Endpoint::Publish(id), Post),
+
+        _ => return Err(RouteError::NotFound)
+    };
+
+    match *method == allowed {
+        true => Ok(endpoint),
+        false => Err(RouteError::MethodNotAllowed(allowed))
+    }
+}
+
+#[derive(RustcEncodable)]
+struct ErrorBody {
+    error: &'static str,
+    message: String
+}
+
+/// An error response; the body is a JSON object with a machine readable
+/// `error` code and a human readable `message`
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub error: &'static str,
+    pub message: String,
+    pub allow: Option<Method>
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: &'static str, message: String) -> ApiError {
+        ApiError {
+            status: status,
+            error: error,
+            message: message,
+            allow: None
+        }
+    }
+
+    pub fn from_route(err: RouteError) -> ApiError {
+        match err {
+            RouteError::NotFound => {
+                ApiError::new(StatusCode::NotFound, "not_found", "No such route".to_owned())
+            }
+
+            RouteError::MethodNotAllowed(method) => {
+                ApiError {
+                    allow: Some(method.clone()),
+                    ..ApiError::new(StatusCode::MethodNotAllowed, "method_not_allowed", format!("Route only supports {}", method))
+                }
+            }
+        }
+    }
+
+    pub fn from_topic(err: TopicError) -> ApiError {
+        ApiError::new(StatusCode::BadRequest, "invalid_topic", err.description())
+    }
+
+    pub fn from_auth(err: AuthError) -> ApiError {
+        let (status, error) = match err {
+            AuthError::MissingToken => (StatusCode::Unauthorized, "missing_token"),
+            AuthError::InvalidToken | AuthError::Expired | AuthError::NotYetValid { .. } => (StatusCode::Unauthorized, "invalid_token"),
+            AuthError::ForbiddenTopic { .. } => (StatusCode::Forbidden, "forbidden_topic")
+        };
+
+        ApiError::new(status, error, err.to_string())
+    }
+
+    pub fn invalid_message(message: String) -> ApiError {
+        ApiError::new(StatusCode::BadRequest, "invalid_message", message)
+    }
+
+    pub fn shutting_down() -> ApiError {
+        ApiError::new(StatusCode::ServiceUnavailable, "shutting_down", "Server is shutting down".to_owned())
+    }
+
+    pub fn too_large(max_size: usize) -> ApiError {
+        ApiError::new(StatusCode::PayloadTooLarge, "payload_too_large", format!("Body must not exceed {} bytes", max_size))
+    }
+
+    pub fn to_json(&self) -> EncodeResult<String> {
+        json::encode(&ErrorBody {
+            error: self.error,
+            message: self.message.clone()
+        })
+    }
+}
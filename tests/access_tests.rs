@@ -0,0 +1,222 @@
+extern crate time;
+extern crate jsonwebtoken as jwt;
+extern crate rustc_serialize;
+extern crate hyper;
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Get, Method, Post};
+    use jwt::{encode, Header};
+    use time::get_time;
+
+    use esper::{Access, TopicRules};
+    use esper::auth::AuthError;
+    use esper::handler::authorize_request;
+    use esper::router::route;
+
+    #[derive(Debug, RustcEncodable, RustcDecodable)]
+    struct Token {
+        pub exp: i64,
+        pub sub: String
+    }
+
+    #[derive(Debug, RustcEncodable, RustcDecodable)]
+    struct RoleToken {
+        pub exp: i64,
+        pub publish: Vec<String>,
+        pub subscribe: Vec<String>
+    }
+
+    fn token(sub: &str, secret: &str) -> Option<String> {
+        let claims = Token {
+            exp: get_time().sec + 3600,
+            sub: sub.to_owned()
+        };
+
+        Some(encode(Header::default(), &claims, secret.as_ref()).unwrap())
+    }
+
+    fn access() -> Access {
+        Access::new("publisher".to_owned(), "subscriber".to_owned())
+    }
+
+    // Routes the request and authorizes it as the handler does
+    fn authorize_query(method: Method, path: &str, topics: Option<&str>, token: Option<String>) -> Result<(), String> {
+        let endpoint = route(&method, path).unwrap();
+
+        authorize_request(&endpoint, topics.map(|ids| ids.to_owned()), token, &TopicRules::default(), &access())
+            .map(|_| ())
+            .map_err(|e| e.message)
+    }
+
+    fn authorize(method: Method, path: &str, token: Option<String>) -> Result<(), String> {
+        authorize_query(method, path, None, token)
+    }
+
+    fn rejected(err: AuthError) -> Result<(), String> {
+        Err(err.to_string())
+    }
+
+    #[test]
+    fn scoped_tokens_are_accepted_for_their_topic() {
+        assert_eq!(Ok(()), authorize(Post, "/publish/abcdef123", token("abcdef123", "publisher")));
+        assert_eq!(Ok(()), authorize(Get, "/subscribe/abcdef123", token("abcdef123", "subscriber")));
+    }
+
+    #[test]
+    fn claims_are_checked_against_the_parsed_topic() {
+        assert_eq!(Ok(()), authorize(Post, "/publish/ABCDEF123", token("abcdef123", "publisher")));
+        assert_eq!(Ok(()), authorize(Get, "/subscribe/ABCDEF123", token("abcdef123", "subscriber")));
+    }
+
+    #[test]
+    fn tokens_for_other_topics_are_forbidden() {
+        let forbidden = rejected(AuthError::ForbiddenTopic {
+            topic: "abcdef456".to_owned(),
+            claim: "sub"
+        });
+
+        assert_eq!(forbidden, authorize(Post, "/publish/abcdef456", token("abcdef123", "publisher")));
+        assert_eq!(forbidden, authorize(Get, "/subscribe/abcdef456", token("abcdef123", "subscriber")));
+    }
+
+    #[test]
+    fn tokens_are_checked_with_the_secret_of_the_route() {
+        assert_eq!(rejected(AuthError::InvalidToken), authorize(Post, "/publish/abcdef123", token("abcdef123", "subscriber")));
+        assert_eq!(rejected(AuthError::InvalidToken), authorize(Get, "/subscribe/abcdef123", token("abcdef123", "publisher")));
+    }
+
+    #[test]
+    fn missing_tokens_are_reported() {
+        assert_eq!(rejected(AuthError::MissingToken), authorize(Post, "/publish/abcdef123", None));
+        assert_eq!(rejected(AuthError::MissingToken), authorize(Get, "/subscribe/abcdef123", None));
+    }
+
+    #[test]
+    fn every_listed_topic_is_authorized() {
+        let forbidden = rejected(AuthError::ForbiddenTopic {
+            topic: "abcdef456".to_owned(),
+            claim: "sub"
+        });
+
+        assert_eq!(Ok(()), authorize(Get, "/subscribe/ABCDEF123,abcdef123", token("abcdef123", "subscriber")));
+        assert_eq!(forbidden, authorize(Get, "/subscribe/abcdef123,abcdef456", token("abcdef123", "subscriber")));
+        assert_eq!(forbidden, authorize_query(Get, "/subscribe", Some("abcdef123,abcdef456"), token("abcdef123", "subscriber")));
+    }
+
+    #[test]
+    fn patterns_are_authorized_as_parsed() {
+        assert_eq!(Ok(()), authorize(Get, "/subscribe/ABCDEF*", token("abcdef*", "subscriber")));
+        assert!(authorize(Get, "/subscribe/abc*", token("abcdef*", "subscriber")).is_err());
+    }
+
+    #[test]
+    fn fan_outs_need_a_token_before_their_body_is_read() {
+        assert_eq!(rejected(AuthError::MissingToken), authorize(Post, "/publish", None));
+        assert_eq!(rejected(AuthError::InvalidToken), authorize(Post, "/publish", token("abcdef123", "subscriber")));
+        assert_eq!(Ok(()), authorize(Post, "/publish", token("abcdef123", "publisher")));
+
+        let open = Access::new(String::new(), "subscriber".to_owned());
+        let endpoint = route(&Post, "/publish").unwrap();
+
+        assert!(authorize_request(&endpoint, None, None, &TopicRules::default(), &open).is_ok());
+    }
+
+    #[test]
+    fn stats_accept_any_valid_publisher_token() {
+        assert_eq!(Ok(()), authorize(Get, "/stats", token("abcdef123", "publisher")));
+        assert_eq!(rejected(AuthError::InvalidToken), authorize(Get, "/stats", token("abcdef123", "subscriber")));
+        assert_eq!(rejected(AuthError::MissingToken), authorize(Get, "/stats", None));
+    }
+
+    #[test]
+    fn boolean_checks_wrap_authorization() {
+        assert!(access().is_authenticated_for_publish("abcdef123".into(), token("abcdef123", "publisher")));
+        assert!(!access().is_authenticated_for_publish("abcdef456".into(), token("abcdef123", "publisher")));
+        assert!(access().is_authenticated_for_subscribe("abcdef123".into(), token("abcdef123", "subscriber")));
+        assert!(!access().is_authenticated_for_subscribe("abcdef123".into(), None));
+    }
+
+    #[test]
+    fn routes_without_a_secret_are_open() {
+        let access = Access::new(String::new(), "subscriber".to_owned());
+
+        assert_eq!(Ok(()), access.authorize_publish(&["abcdef123"], None));
+        assert_eq!(Err(AuthError::MissingToken), access.authorize_subscribe(&["abcdef123"], None));
+    }
+
+    #[test]
+    fn role_grants_are_checked_per_route() {
+        let claims = RoleToken {
+            exp: get_time().sec + 3600,
+            publish: vec!["org123news".to_owned()],
+            subscribe: vec!["org123*".to_owned()]
+        };
+
+        let access = Access::new("secret".to_owned(), "secret".to_owned());
+        let token = encode(Header::default(), &claims, "secret".as_ref()).unwrap();
+
+        assert_eq!(Ok(()), access.authorize_publish(&["org123news"], Some(token.clone())));
+        assert!(access.authorize_publish(&["org123alerts"], Some(token.clone())).is_err());
+        assert_eq!(Ok(()), access.authorize_subscribe(&["org123alerts"], Some(token)));
+    }
+
+    #[test]
+    fn leeway_applies_to_both_routes() {
+        let claims = Token {
+            exp: get_time().sec - 30,
+            sub: "abcdef123".to_owned()
+        };
+
+        let token = encode(Header::default(), &claims, "secret".as_ref()).unwrap();
+        let strict = Access::new("secret".to_owned(), "secret".to_owned());
+        let lenient = Access::new("secret".to_owned(), "secret".to_owned()).with_leeway(60);
+
+        assert_eq!(Err(AuthError::Expired), strict.authorize_publish(&["abcdef123"], Some(token.clone())));
+        assert_eq!(Err(AuthError::Expired), strict.authorize_subscribe(&["abcdef123"], Some(token.clone())));
+        assert_eq!(Ok(()), lenient.authorize_publish(&["abcdef123"], Some(token.clone())));
+        assert_eq!(Ok(()), lenient.authorize_subscribe(&["abcdef123"], Some(token)));
+    }
+
+    #[test]
+    fn tokens_are_found_in_the_authorization_header() {
+        let access = access();
+
+        assert_eq!(Some("abc".to_owned()), access.find_token(Some("Bearer abc"), None, None));
+        assert_eq!(Some("abc".to_owned()), access.find_token(Some("bearer  abc "), None, None));
+        assert_eq!(None, access.find_token(Some("Basic YWJjOmRlZg=="), None, None));
+        assert_eq!(None, access.find_token(Some("Bearer "), None, None));
+    }
+
+    #[test]
+    fn tokens_are_found_in_the_configured_cookie() {
+        let with_cookie = access().with_token_cookie("esper_token".to_owned());
+
+        assert_eq!(Some("abc".to_owned()), with_cookie.find_token(None, Some("theme=dark; esper_token=abc"), None));
+        assert_eq!(Some("abc".to_owned()), with_cookie.find_token(None, Some("esper_token=\"abc\""), None));
+        assert_eq!(None, with_cookie.find_token(None, Some("other_token=abc"), None));
+
+        // Cookies are only read once a name is configured
+        assert_eq!(None, access().find_token(None, Some("esper_token=abc"), None));
+    }
+
+    #[test]
+    fn header_wins_over_cookie_over_query() {
+        let access = access().with_token_cookie("esper_token".to_owned());
+        let query = Some("query".to_owned());
+
+        assert_eq!(Some("header".to_owned()), access.find_token(Some("Bearer header"), Some("esper_token=cookie"), query.clone()));
+        assert_eq!(Some("cookie".to_owned()), access.find_token(None, Some("esper_token=cookie"), query.clone()));
+        assert_eq!(Some("cookie".to_owned()), access.find_token(Some("Basic abc"), Some("esper_token=cookie"), query.clone()));
+        assert_eq!(Some("query".to_owned()), access.find_token(None, None, query));
+    }
+
+    #[test]
+    fn query_tokens_can_be_disabled() {
+        let access = access().without_query_tokens();
+
+        assert_eq!(None, access.find_token(None, None, Some("query".to_owned())));
+        assert_eq!(Some("header".to_owned()), access.find_token(Some("Bearer header"), None, Some("query".to_owned())));
+    }
+}
@@ -0,0 +1,77 @@
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use esper::{IdPolicy, Manager, Options, PublishError, Topic};
+    use esper::message::Event;
+
+    fn manager(policy: IdPolicy) -> Manager {
+        Manager::with_options(Options {
+            id_policy: policy,
+            ..Options::default()
+        })
+    }
+
+    fn topic() -> Topic {
+        Topic::validate(0, "abcdef123".to_owned()).unwrap()
+    }
+
+    #[test]
+    fn json_array_batch_keeps_order() {
+        let events = Event::from_json_array(br#"[{"data": "a"}, {"event": "x", "data": "b"}]"#).unwrap();
+
+        assert_eq!(2, events.len());
+        assert_eq!(Some("a".to_owned()), events[0].data);
+        assert_eq!(Some("x".to_owned()), events[1].event);
+    }
+
+    #[test]
+    fn json_array_batch_reports_invalid_event() {
+        let err = Event::from_json_array(br#"[{"data": "a"}, {"event": "x"}]"#).unwrap_err();
+
+        assert!(err.starts_with("Event 1:"));
+    }
+
+    #[test]
+    fn json_array_batch_must_be_array_and_non_empty() {
+        assert!(Event::from_json_array(br#"{"data": "a"}"#).is_err());
+        assert!(Event::from_json_array(b"[]").is_err());
+    }
+
+    #[test]
+    fn ndjson_batch_skips_blank_lines() {
+        let events = Event::from_ndjson(b"{\"data\": \"a\"}\n\n{\"data\": \"b\"}\n").unwrap();
+
+        assert_eq!(2, events.len());
+        assert_eq!(Some("b".to_owned()), events[1].data);
+    }
+
+    #[test]
+    fn ndjson_batch_reports_invalid_line() {
+        assert!(Event::from_ndjson(b"{\"data\": \"a\"}\nnope").is_err());
+        assert!(Event::from_ndjson(b"\n").is_err());
+    }
+
+    #[test]
+    fn publish_batch_assigns_ids_in_order() {
+        let mgr = manager(IdPolicy::Override);
+        let msgs = vec![b"data: a".to_vec(), b"data: b".to_vec(), b"data: c".to_vec()];
+
+        let ids: Vec<Option<String>> = mgr.publish_batch(topic(), &msgs).unwrap().into_iter().map(|r| r.id).collect();
+
+        assert_eq!(vec![Some("1".to_owned()), Some("2".to_owned()), Some("3".to_owned())], ids);
+        assert_eq!(2, mgr.replay(&topic(), "1").len());
+    }
+
+    #[test]
+    fn publish_batch_is_all_or_nothing() {
+        let mgr = manager(IdPolicy::Reject);
+        let msgs = vec![b"data: a".to_vec(), b"id: 5\ndata: b".to_vec()];
+
+        assert_eq!(PublishError::IdNotAllowed, mgr.publish_batch(topic(), &msgs).unwrap_err());
+        assert_eq!(0, mgr.replay(&topic(), "unknown").len());
+
+        // The rejected batch must not have used up any IDs
+        assert_eq!(Some("1".to_owned()), mgr.publish(topic(), &b"data: c".to_vec()).unwrap().id);
+    }
+}
@@ -0,0 +1,103 @@
+extern crate hyper;
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io;
+
+    use hyper::StatusCode;
+
+    use esper::handler::{read_body, Body};
+    use esper::router::ApiError;
+
+    // Reads as a transport would: the given chunks, no more than fits the
+    // buffer at once, then the end of the body
+    fn reads(chunks: Vec<io::Result<Vec<u8>>>) -> Box<FnMut(&mut [u8]) -> io::Result<usize>> {
+        let mut chunks: VecDeque<io::Result<Vec<u8>>> = chunks.into_iter().collect();
+
+        Box::new(move |buf: &mut [u8]| {
+            match chunks.pop_front() {
+                Some(Ok(chunk)) => {
+                    let n = ::std::cmp::min(buf.len(), chunk.len());
+
+                    buf[..n].copy_from_slice(&chunk[..n]);
+
+                    if n < chunk.len() {
+                        chunks.push_front(Ok(chunk[n..].to_vec()));
+                    }
+
+                    Ok(n)
+                }
+
+                Some(Err(e)) => Err(e),
+                None => Ok(0)
+            }
+        })
+    }
+
+    fn would_block() -> io::Result<Vec<u8>> {
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "would block"))
+    }
+
+    fn read_all(chunks: Vec<io::Result<Vec<u8>>>, body: Body, max_size: usize) -> Result<Option<Vec<u8>>, ApiError> {
+        read_body(reads(chunks), body, max_size, &mut Vec::new())
+    }
+
+    #[test]
+    fn bodies_with_a_length_end_after_it() {
+        // Anything after the announced length is left unread
+        let body = read_all(vec![Ok(b"data: a".to_vec()), Ok(b"data: b".to_vec())], Body::Len(7), 64);
+
+        assert_eq!(Some(b"data: a".to_vec()), body.unwrap());
+    }
+
+    #[test]
+    fn chunked_bodies_end_with_the_transport() {
+        let body = read_all(vec![Ok(b"data: ".to_vec()), Ok(b"a".to_vec())], Body::Chunked, 64);
+
+        assert_eq!(Some(b"data: a".to_vec()), body.unwrap());
+    }
+
+    #[test]
+    fn bodies_exactly_at_the_limit_are_read() {
+        let data = vec![b'x'; 64];
+
+        assert_eq!(Some(data.clone()), read_all(vec![Ok(data.clone())], Body::Len(64), 64).unwrap());
+        assert_eq!(Some(data.clone()), read_all(vec![Ok(data)], Body::Chunked, 64).unwrap());
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_too_large() {
+        let err = read_all(vec![Ok(vec![b'x'; 40]), Ok(vec![b'x'; 25])], Body::Chunked, 64).unwrap_err();
+
+        assert_eq!(StatusCode::PayloadTooLarge, err.status);
+    }
+
+    #[test]
+    fn bodies_shorter_than_their_length_are_rejected() {
+        let err = read_all(vec![Ok(b"data: a".to_vec())], Body::Len(10), 64).unwrap_err();
+
+        assert_eq!(StatusCode::BadRequest, err.status);
+        assert_eq!("Body is shorter than its Content-Length", err.message);
+    }
+
+    #[test]
+    fn bodies_span_several_reads() {
+        let data: Vec<u8> = (0..10000).map(|n| (n % 251) as u8).collect();
+
+        assert_eq!(Some(data.clone()), read_all(vec![Ok(data.clone())], Body::Len(10000), 65536).unwrap());
+        assert_eq!(Some(data.clone()), read_all(vec![Ok(data)], Body::Chunked, 65536).unwrap());
+    }
+
+    #[test]
+    fn reading_resumes_where_it_blocked() {
+        let mut buf = Vec::new();
+        let mut read = reads(vec![Ok(b"data: ".to_vec()), would_block(), Ok(b"a".to_vec())]);
+
+        assert_eq!(None, read_body(&mut read, Body::Len(7), 64, &mut buf).unwrap());
+        assert_eq!(b"data: ".to_vec(), buf);
+        assert_eq!(Some(b"data: a".to_vec()), read_body(&mut read, Body::Len(7), 64, &mut buf).unwrap());
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use esper::{IdPolicy, Manager, Message, Options, PublishError, Topic};
+
+    fn manager(policy: IdPolicy) -> Manager {
+        Manager::with_options(Options {
+            id_policy: policy,
+            ..Options::default()
+        })
+    }
+
+    fn topic(id: &str) -> Topic {
+        Topic::validate(0, id.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn message_with_id_replaces_existing_ids() {
+        let msg = Message::with_id(&b"id: 99\ndata: hello".to_vec(), "7");
+
+        assert_eq!(Some("7"), msg.id());
+        assert_eq!(b"id: 7\ndata: hello\n\n".to_vec().as_slice(), msg.as_slice());
+    }
+
+    #[test]
+    fn publisher_policy_keeps_publisher_ids() {
+        let mgr = manager(IdPolicy::Publisher);
+
+        let receipt = mgr.publish(topic("abcdef123"), &b"id: abc\ndata: a".to_vec()).unwrap();
+        assert_eq!(Some("abc".to_owned()), receipt.id);
+
+        let receipt = mgr.publish(topic("abcdef123"), &b"data: a".to_vec()).unwrap();
+        assert_eq!(None, receipt.id);
+    }
+
+    #[test]
+    fn override_policy_assigns_ids_per_topic() {
+        let mgr = manager(IdPolicy::Override);
+
+        assert_eq!(Some("1".to_owned()), mgr.publish(topic("abcdef123"), &b"data: a".to_vec()).unwrap().id);
+        assert_eq!(Some("2".to_owned()), mgr.publish(topic("abcdef123"), &b"id: x\ndata: b".to_vec()).unwrap().id);
+        assert_eq!(Some("1".to_owned()), mgr.publish(topic("abcdef456"), &b"data: c".to_vec()).unwrap().id);
+    }
+
+    #[test]
+    fn reject_policy_refuses_publisher_ids() {
+        let mgr = manager(IdPolicy::Reject);
+
+        assert_eq!(PublishError::IdNotAllowed, mgr.publish(topic("abcdef123"), &b"id: x\ndata: a".to_vec()).unwrap_err());
+        assert_eq!(Some("1".to_owned()), mgr.publish(topic("abcdef123"), &b"data: a".to_vec()).unwrap().id);
+    }
+
+    #[test]
+    fn assigned_ids_can_be_replayed() {
+        let mgr = manager(IdPolicy::Override);
+
+        mgr.publish(topic("abcdef123"), &b"data: a".to_vec()).unwrap();
+        mgr.publish(topic("abcdef123"), &b"data: b".to_vec()).unwrap();
+
+        let replayed = mgr.replay(&topic("abcdef123"), "1");
+
+        assert_eq!(1, replayed.len());
+        assert_eq!(Some("2"), replayed[0].id());
+    }
+
+    #[test]
+    fn sequences_are_forgotten_with_their_topic() {
+        // One topic per shard, without any history retained
+        let mgr = Manager::with_options(Options {
+            id_policy: IdPolicy::Override,
+            history_size: 0,
+            history_topics: 16,
+            ..Options::default()
+        });
+
+        assert_eq!(Some("1".to_owned()), mgr.publish(topic("busytopic"), &b"data: a".to_vec()).unwrap().id);
+        assert_eq!(Some("2".to_owned()), mgr.publish(topic("busytopic"), &b"data: b".to_vec()).unwrap().id);
+
+        for n in 0..64 {
+            mgr.publish(topic(&format!("abcdef{:03}", n)), &b"data: c".to_vec()).unwrap();
+        }
+
+        assert_eq!(Some("1".to_owned()), mgr.publish(topic("busytopic"), &b"data: d".to_vec()).unwrap().id);
+    }
+
+    #[test]
+    fn recently_published_sequences_are_kept() {
+        let mgr = Manager::with_options(Options {
+            id_policy: IdPolicy::Override,
+            history_size: 0,
+            history_topics: 32,
+            ..Options::default()
+        });
+
+        for n in 0..64 {
+            mgr.publish(topic("busytopic"), &b"data: a".to_vec()).unwrap();
+            mgr.publish(topic(&format!("abcdef{:03}", n)), &b"data: b".to_vec()).unwrap();
+        }
+
+        assert_eq!(Some("65".to_owned()), mgr.publish(topic("busytopic"), &b"data: c".to_vec()).unwrap().id);
+    }
+
+    #[test]
+    fn id_policy_from_str() {
+        assert_eq!(Ok(IdPolicy::Publisher), "off".parse());
+        assert_eq!(Ok(IdPolicy::Override), "override".parse());
+        assert_eq!(Ok(IdPolicy::Reject), "reject".parse());
+        assert!("bogus".parse::<IdPolicy>().is_err());
+    }
+}
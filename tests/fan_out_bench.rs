@@ -0,0 +1,86 @@
+extern crate hyper;
+extern crate esper;
+
+// Fan-out benchmark; run with `cargo test --release -- --ignored --nocapture`.
+// Publishing queues a reference to one shared frame for every subscriber, so
+// the cost per subscriber must stay flat as subscribers are added.
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use hyper::Next;
+
+    use esper::{Client, Manager, Notify, Topic};
+
+    const ROUNDS: usize = 10;
+
+    struct Ignore;
+
+    impl Notify for Ignore {
+        fn notify(&self, _next: Next) -> bool {
+            true
+        }
+    }
+
+    fn topic() -> Topic {
+        Topic::validate(0, "abcdef123".to_owned()).unwrap()
+    }
+
+    // Nanoseconds spent publishing a 2 KiB event per subscriber
+    fn fan_out(subscribers: usize) -> f64 {
+        let mgr = Manager::new();
+        let clients: Vec<Client> = (0..subscribers).map(|_| Client::new()).collect();
+        let mut event = b"data: ".to_vec();
+
+        event.resize(2048, b'x');
+
+        for client in clients.iter() {
+            mgr.subscribe(client.clone(), topic(), Ignore, None);
+        }
+
+        let start = Instant::now();
+
+        for _ in 0..ROUNDS {
+            mgr.publish(topic(), &event).unwrap();
+        }
+
+        let elapsed = start.elapsed();
+
+        // Every subscriber was queued the very frames retained for replay
+        let frames: Vec<*const u8> = mgr.replay(&topic(), "unknown").iter().map(|msg| msg.as_slice().as_ptr()).collect();
+
+        assert_eq!(ROUNDS, frames.len());
+
+        for client in clients {
+            let queued: Vec<*const u8> = mgr.messages_for(client).iter().map(|msg| msg.as_slice().as_ptr()).collect();
+
+            assert_eq!(frames, queued);
+        }
+
+        (elapsed.as_secs() as f64 * 1e9 + elapsed.subsec_nanos() as f64) / (subscribers * ROUNDS) as f64
+    }
+
+    #[test]
+    fn subscribers_share_one_frame() {
+        fan_out(100);
+    }
+
+    #[test]
+    #[ignore]
+    fn fan_out_cost_is_flat_per_subscriber() {
+        // Warm up the allocator before measuring
+        fan_out(100);
+
+        let costs: Vec<(usize, f64)> = [100, 1000, 10000].iter().map(|&n| (n, fan_out(n))).collect();
+
+        for &(n, cost) in costs.iter() {
+            println!("publish of 2 KiB to {} subscribers: {:.1} ns/subscriber", n, cost);
+        }
+
+        // Copying the event for each subscriber would grow with the event
+        // size, contention or rehashing with the number of subscribers
+        let smallest = costs[0].1;
+
+        assert!(costs.iter().all(|&(_, cost)| cost < smallest * 5.0 + 500.0));
+    }
+}
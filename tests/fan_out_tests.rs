@@ -0,0 +1,106 @@
+extern crate hyper;
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use hyper::Next;
+
+    use esper::{Client, IdPolicy, Manager, Message, Notify, Options, PublishError, Topic};
+
+    #[derive(Clone)]
+    struct Stub;
+
+    impl Notify for Stub {
+        fn notify(&self, _next: Next) -> bool {
+            true
+        }
+    }
+
+    fn manager(policy: IdPolicy) -> Manager {
+        Manager::with_options(Options {
+            id_policy: policy,
+            ..Options::default()
+        })
+    }
+
+    fn topic(id: &str) -> Topic {
+        Topic::validate(0, id.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn publish_to_delivers_to_every_topic() {
+        let mgr = manager(IdPolicy::Override);
+        let topics = vec![topic("abcdef123"), topic("abcdef456")];
+
+        let receipts = mgr.publish_to(&topics, &b"data: a".to_vec()).unwrap();
+
+        assert_eq!(2, receipts.len());
+        assert_eq!("abcdef123", receipts[0].topic);
+        assert_eq!("abcdef456", receipts[1].topic);
+        assert_eq!(1, mgr.replay(&topic("abcdef123"), "unknown").len());
+        assert_eq!(1, mgr.replay(&topic("abcdef456"), "unknown").len());
+    }
+
+    #[test]
+    fn publish_to_skips_duplicate_topics() {
+        let mgr = manager(IdPolicy::Override);
+        let topics = vec![topic("abcdef123"), topic("abcdef123")];
+
+        assert_eq!(1, mgr.publish_to(&topics, &b"data: a".to_vec()).unwrap().len());
+        assert_eq!(1, mgr.replay(&topic("abcdef123"), "unknown").len());
+    }
+
+    #[test]
+    fn publish_to_rejects_before_publishing_anything() {
+        let mgr = manager(IdPolicy::Reject);
+        let topics = vec![topic("abcdef123"), topic("abcdef456")];
+
+        assert_eq!(PublishError::IdNotAllowed, mgr.publish_to(&topics, &b"id: 1\ndata: a".to_vec()).unwrap_err());
+        assert_eq!(0, mgr.replay(&topic("abcdef123"), "unknown").len());
+    }
+
+    fn data(message: &Message) -> String {
+        let body = String::from_utf8(message.as_slice().to_vec()).unwrap();
+
+        body.lines().find(|line| line.starts_with("data: ")).unwrap().to_owned()
+    }
+
+    #[test]
+    fn publish_to_delivers_to_every_topic_at_once() {
+        let mgr = Arc::new(manager(IdPolicy::Publisher));
+        let client = Client::new();
+
+        mgr.subscribe_all(client.clone(), &[topic("abcdef123"), topic("abcdef456")], Stub);
+
+        let handles: Vec<_> = (0..2).map(|n| {
+            let mgr = mgr.clone();
+
+            thread::spawn(move || {
+                let topics = match n {
+                    0 => vec![topic("abcdef123"), topic("abcdef456")],
+                    _ => vec![topic("abcdef456"), topic("abcdef123")]
+                };
+
+                for i in 0..50 {
+                    mgr.publish_to(&topics, &format!("data: {}-{}", n, i).into_bytes()).unwrap();
+                }
+            })
+        }).collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let messages = mgr.messages_for(client);
+
+        // Nothing is published to either topic between the two copies
+        assert_eq!(200, messages.len());
+
+        for pair in messages.chunks(2) {
+            assert_eq!(data(&pair[0]), data(&pair[1]));
+        }
+    }
+}
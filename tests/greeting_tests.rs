@@ -0,0 +1,62 @@
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use esper::{Greeting, Options};
+    use esper::pattern::glob_matches;
+
+    fn greeting(retry: Option<u64>, padding: usize) -> Greeting {
+        Greeting {
+            retry: retry,
+            padding: padding
+        }
+    }
+
+    #[test]
+    fn default_greeting_is_an_empty_comment() {
+        assert_eq!(b":\n\n".to_vec(), Greeting::default().to_frame());
+    }
+
+    #[test]
+    fn greeting_with_retry_and_padding() {
+        assert_eq!(b":\nretry: 3000\n\n".to_vec(), greeting(Some(3000), 0).to_frame());
+        assert_eq!(b":    \nretry: 3000\n\n".to_vec(), greeting(Some(3000), 4).to_frame());
+        assert_eq!(2048 + 3, greeting(None, 2048).to_frame().len());
+    }
+
+    #[test]
+    fn greeting_for_topics() {
+        let options = Options {
+            greeting: greeting(Some(3000), 0),
+            topic_greetings: vec![
+                ("abcdef123".to_owned(), greeting(Some(1000), 0)),
+                ("org*".to_owned(), greeting(Some(500), 2048)),
+                ("*".to_owned(), greeting(Some(100), 0))
+            ],
+            ..Options::default()
+        };
+
+        assert_eq!(&greeting(Some(1000), 0), options.greeting_for("abcdef123"));
+        assert_eq!(&greeting(Some(500), 2048), options.greeting_for("org12345"));
+        assert_eq!(&greeting(Some(500), 2048), options.greeting_for("org*"));
+        assert_eq!(&greeting(Some(100), 0), options.greeting_for("abcdef456"));
+    }
+
+    #[test]
+    fn greeting_falls_back_to_global() {
+        let options = Options {
+            greeting: greeting(Some(3000), 0),
+            topic_greetings: vec![("abcdef123".to_owned(), greeting(Some(1000), 0))],
+            ..Options::default()
+        };
+
+        assert_eq!(&greeting(Some(3000), 0), options.greeting_for("abcdef456"));
+    }
+
+    #[test]
+    fn globs_without_wildcards_match_exactly() {
+        assert!(glob_matches("abcdef123", "abcdef123"));
+        assert!(!glob_matches("abcdef123", "abcdef1234"));
+        assert!(glob_matches("abc*", "abcdef1234"));
+    }
+}
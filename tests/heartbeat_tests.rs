@@ -0,0 +1,107 @@
+extern crate hyper;
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use hyper::Next;
+
+    use esper::{Client, Manager, Message, Notify, Queue, Topic};
+
+    #[derive(Clone)]
+    struct Wakeups(Arc<AtomicUsize>, bool);
+
+    impl Notify for Wakeups {
+        fn notify(&self, _next: Next) -> bool {
+            self.0.fetch_add(1, Ordering::Relaxed);
+
+            self.1
+        }
+    }
+
+    fn topic(id: &str) -> Topic {
+        Topic::validate(0, id.to_owned()).unwrap()
+    }
+
+    fn ping() -> Message {
+        Message::new(&b": ping".to_vec())
+    }
+
+    #[test]
+    fn idle_subscribers_receive_a_heartbeat() {
+        let mgr = Manager::new();
+        let client = Client::new();
+        let wakeups = Wakeups(Arc::new(AtomicUsize::new(0)), true);
+
+        mgr.subscribe(client.clone(), topic("abcdef123"), wakeups.clone(), None);
+
+        assert_eq!(1, mgr.heartbeat(Duration::from_secs(0)));
+        assert_eq!(1, wakeups.0.load(Ordering::Relaxed));
+
+        let msgs = mgr.messages_for(client);
+
+        assert_eq!(1, msgs.len());
+        assert_eq!(b": ping\n\n", msgs[0].as_slice());
+    }
+
+    #[test]
+    fn heartbeats_wait_for_the_interval() {
+        let mgr = Manager::new();
+        let wakeups = Wakeups(Arc::new(AtomicUsize::new(0)), true);
+
+        mgr.subscribe(Client::new(), topic("abcdef123"), wakeups.clone(), None);
+
+        assert_eq!(0, mgr.heartbeat(Duration::from_secs(60)));
+        assert_eq!(0, wakeups.0.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn subscribers_with_pending_messages_are_not_idle() {
+        let mgr = Manager::new();
+        let wakeups = Wakeups(Arc::new(AtomicUsize::new(0)), true);
+
+        mgr.subscribe(Client::new(), topic("abcdef123"), wakeups.clone(), None);
+        mgr.publish(topic("abcdef123"), &b"data: a".to_vec()).unwrap();
+
+        assert_eq!(0, mgr.heartbeat(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn one_heartbeat_per_client() {
+        let mgr = Manager::new();
+        let client = Client::new();
+        let wakeups = Wakeups(Arc::new(AtomicUsize::new(0)), true);
+
+        mgr.subscribe_all(client.clone(), &[topic("abcdef123"), topic("abcdef456")], wakeups.clone());
+
+        assert_eq!(1, mgr.heartbeat(Duration::from_secs(0)));
+        assert_eq!(1, mgr.messages_for(client).len());
+    }
+
+    #[test]
+    fn dead_subscribers_are_removed() {
+        let mgr = Manager::new();
+        let wakeups = Wakeups(Arc::new(AtomicUsize::new(0)), false);
+
+        mgr.subscribe(Client::new(), topic("abcdef123"), wakeups.clone(), None);
+
+        assert_eq!(0, mgr.heartbeat(Duration::from_secs(0)));
+        assert_eq!(0, mgr.stats().clients);
+        assert_eq!(0, mgr.stats().topics);
+    }
+
+    #[test]
+    fn queue_heartbeat() {
+        let mut queue = Queue::new();
+
+        assert!(!queue.heartbeat(&ping(), Duration::from_secs(60)));
+        assert!(queue.heartbeat(&ping(), Duration::from_secs(0)));
+
+        // Still pending, so there is no need for another
+        assert!(!queue.heartbeat(&ping(), Duration::from_secs(0)));
+        assert_eq!(1, queue.take().len());
+    }
+}
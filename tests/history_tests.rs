@@ -0,0 +1,120 @@
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use esper::{History, Manager, Message, Options, Topic};
+
+    fn message(body: &str) -> Message {
+        Message::new(&body.as_bytes().to_vec())
+    }
+
+    fn ids(msgs: Vec<Message>) -> Vec<String> {
+        msgs.iter().map(|msg| msg.id().unwrap().to_owned()).collect()
+    }
+
+    #[test]
+    fn message_id_is_parsed_from_body() {
+        assert_eq!(Some("42"), message("id: 42\ndata: hello").id());
+        assert_eq!(Some("42"), message("data: hello\nid:42").id());
+        assert_eq!(None, message("data: hello").id());
+    }
+
+    #[test]
+    fn history_replays_messages_after_last_event_id() {
+        let mut history = History::new(10);
+
+        history.push(message("id: 1\ndata: a"));
+        history.push(message("id: 2\ndata: b"));
+        history.push(message("id: 3\ndata: c"));
+
+        assert_eq!(vec!["2", "3"], ids(history.since("1")));
+        assert_eq!(Vec::<String>::new(), ids(history.since("3")));
+    }
+
+    #[test]
+    fn history_replays_everything_for_unknown_id() {
+        let mut history = History::new(10);
+
+        history.push(message("id: 1\ndata: a"));
+        history.push(message("id: 2\ndata: b"));
+
+        assert_eq!(vec!["1", "2"], ids(history.since("unknown")));
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut history = History::new(2);
+
+        history.push(message("id: 1\ndata: a"));
+        history.push(message("id: 2\ndata: b"));
+        history.push(message("id: 3\ndata: c"));
+
+        assert_eq!(vec!["2", "3"], ids(history.since("unknown")));
+    }
+
+    #[test]
+    fn manager_retains_published_messages_per_topic() {
+        let mgr = Manager::new();
+        let topic = Topic::validate(0, "abcdef123".to_owned()).unwrap();
+        let other = Topic::validate(0, "abcdef456".to_owned()).unwrap();
+
+        mgr.publish(topic.clone(), &b"id: 1\ndata: a".to_vec()).unwrap();
+        mgr.publish(topic.clone(), &b"id: 2\ndata: b".to_vec()).unwrap();
+        mgr.publish(other.clone(), &b"id: 3\ndata: c".to_vec()).unwrap();
+
+        assert_eq!(vec!["2"], ids(mgr.replay(&topic, "1")));
+        assert_eq!(vec!["3"], ids(mgr.replay(&other, "1")));
+    }
+
+    fn limited(history_topics: usize) -> Manager {
+        Manager::with_options(Options {
+            history_topics: history_topics,
+            ..Options::default()
+        })
+    }
+
+    fn retained(mgr: &Manager, topics: &[Topic]) -> usize {
+        topics.iter().filter(|topic| !mgr.replay(topic, "unknown").is_empty()).count()
+    }
+
+    #[test]
+    fn histories_are_kept_for_a_limited_number_of_topics() {
+        let mgr = limited(16);
+        let topics: Vec<Topic> = (0..64).map(|n| Topic::validate(0, format!("abcdef{:03}", n)).unwrap()).collect();
+
+        for topic in topics.iter() {
+            mgr.publish(topic.clone(), &b"id: 1\ndata: a".to_vec()).unwrap();
+        }
+
+        assert!(retained(&mgr, &topics) <= 16);
+        assert_eq!(vec!["1"], ids(mgr.replay(&topics[63], "unknown")));
+    }
+
+    #[test]
+    fn least_recently_published_histories_are_evicted_first() {
+        let mgr = limited(32);
+        let busy = Topic::validate(0, "busytopic".to_owned()).unwrap();
+        let topics: Vec<Topic> = (0..64).map(|n| Topic::validate(0, format!("abcdef{:03}", n)).unwrap()).collect();
+
+        for topic in topics.iter() {
+            mgr.publish(busy.clone(), &b"id: 1\ndata: a".to_vec()).unwrap();
+            mgr.publish(topic.clone(), &b"id: 2\ndata: b".to_vec()).unwrap();
+        }
+
+        // Evicting the busy topic would have dropped its earlier messages
+        assert_eq!(mgr.replay(&busy, "unknown").len(), 64);
+        assert!(retained(&mgr, &topics) < 64);
+    }
+
+    #[test]
+    fn histories_of_every_topic_are_kept_without_a_limit() {
+        let mgr = limited(0);
+        let topics: Vec<Topic> = (0..64).map(|n| Topic::validate(0, format!("abcdef{:03}", n)).unwrap()).collect();
+
+        for topic in topics.iter() {
+            mgr.publish(topic.clone(), &b"id: 1\ndata: a".to_vec()).unwrap();
+        }
+
+        assert_eq!(retained(&mgr, &topics), 64);
+    }
+}
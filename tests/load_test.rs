@@ -0,0 +1,200 @@
+extern crate hyper;
+extern crate esper;
+
+// Load test; run with `cargo test --release -- --ignored --nocapture`.
+// Thousands of subscribers are spread over a number of topics while several
+// threads publish and others drain subscriber queues, as the server threads
+// writing to connections would.
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use hyper::Next;
+
+    use esper::{Client, Manager, Notify, Topic};
+
+    const TOPICS: usize = 100;
+    const SUBSCRIBERS: usize = 10000;
+    const PUBLISHERS: usize = 4;
+    const PUBLISHES: usize = 1000;
+
+    #[derive(Clone)]
+    struct Wakeups(Arc<AtomicUsize>);
+
+    impl Notify for Wakeups {
+        fn notify(&self, _next: Next) -> bool {
+            self.0.fetch_add(1, Ordering::Relaxed);
+
+            true
+        }
+    }
+
+    // Holds up the publish that wakes it until it is released
+    struct Blocking {
+        entered: Sender<()>,
+        release: Mutex<Receiver<()>>
+    }
+
+    impl Notify for Blocking {
+        fn notify(&self, _next: Next) -> bool {
+            self.entered.send(()).unwrap();
+            self.release.lock().unwrap().recv_timeout(Duration::from_secs(10)).is_ok()
+        }
+    }
+
+    fn topic(index: usize) -> Topic {
+        Topic::validate(0, format!("topic{:05}", index)).unwrap()
+    }
+
+    fn micros(duration: Duration) -> u64 {
+        duration.as_secs() * 1000000 + duration.subsec_nanos() as u64 / 1000
+    }
+
+    #[test]
+    #[ignore]
+    fn publish_latency_under_load() {
+        let mgr = Arc::new(Manager::new());
+        let wakeups = Wakeups(Arc::new(AtomicUsize::new(0)));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let clients: Vec<Client> = (0..SUBSCRIBERS).map(|index| {
+            let client = Client::new();
+
+            mgr.subscribe(client.clone(), topic(index % TOPICS), wakeups.clone(), None);
+
+            client
+        }).collect();
+
+        // Drain queues concurrently like the server threads writing to clients
+        let drainers: Vec<_> = clients.chunks(SUBSCRIBERS / 4).map(|chunk| {
+            let mgr = mgr.clone();
+            let done = done.clone();
+            let chunk = chunk.to_vec();
+
+            thread::spawn(move || {
+                let mut received = 0;
+
+                while !done.load(Ordering::Relaxed) {
+                    for client in chunk.iter() {
+                        received += mgr.messages_for(client.clone()).len();
+                    }
+                }
+
+                received
+            })
+        }).collect();
+
+        let publishers: Vec<_> = (0..PUBLISHERS).map(|publisher| {
+            let mgr = mgr.clone();
+
+            thread::spawn(move || {
+                let body = vec![65 as u8; 2048];
+                let mut latencies = Vec::with_capacity(PUBLISHES);
+
+                for index in 0..PUBLISHES {
+                    let start = Instant::now();
+
+                    let receipt = mgr.publish(topic((publisher + index * PUBLISHERS) % TOPICS), &body).unwrap();
+
+                    latencies.push(micros(start.elapsed()));
+
+                    assert_eq!(SUBSCRIBERS / TOPICS, receipt.subscribers);
+                }
+
+                latencies
+            })
+        }).collect();
+
+        let mut latencies: Vec<u64> = publishers.into_iter().flat_map(|handle| handle.join().unwrap()).collect();
+
+        done.store(true, Ordering::Relaxed);
+
+        let received: usize = drainers.into_iter().map(|handle| handle.join().unwrap()).sum();
+
+        latencies.sort();
+
+        println!("{} subscribers over {} topics, {} publishes", SUBSCRIBERS, TOPICS, latencies.len());
+        println!("publish latency p50: {} us", latencies[latencies.len() / 2]);
+        println!("publish latency p99: {} us", latencies[latencies.len() * 99 / 100]);
+        println!("publish latency max: {} us", latencies[latencies.len() - 1]);
+        println!("messages drained: {}, wakeups: {}", received, wakeups.0.load(Ordering::Relaxed));
+
+        assert_eq!(PUBLISHERS * PUBLISHES * SUBSCRIBERS / TOPICS, wakeups.0.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    #[ignore]
+    fn subscribing_while_publishing() {
+        let mgr = Arc::new(Manager::new());
+        let wakeups = Wakeups(Arc::new(AtomicUsize::new(0)));
+
+        let subscribers: Vec<_> = (0..4).map(|_| {
+            let mgr = mgr.clone();
+            let wakeups = wakeups.clone();
+
+            thread::spawn(move || {
+                let start = Instant::now();
+
+                for index in 0..SUBSCRIBERS / 4 {
+                    let client = Client::new();
+
+                    mgr.subscribe(client.clone(), topic(index % TOPICS), wakeups.clone(), None);
+                    mgr.messages_for(client);
+                }
+
+                micros(start.elapsed())
+            })
+        }).collect();
+
+        for index in 0..PUBLISHES {
+            mgr.publish(topic(index % TOPICS), &b"data: a".to_vec()).unwrap();
+        }
+
+        for handle in subscribers {
+            println!("{} subscribes took {} us", SUBSCRIBERS / 4, handle.join().unwrap());
+        }
+
+        assert_eq!(SUBSCRIBERS, mgr.stats().clients);
+    }
+
+    #[test]
+    fn publishes_to_other_shards_are_not_held_up() {
+        let mgr = Arc::new(Manager::new());
+        let (entered_tx, entered_rx) = channel();
+        let (release_tx, release_rx) = channel();
+
+        mgr.subscribe(Client::new(), topic(0), Blocking { entered: entered_tx, release: Mutex::new(release_rx) }, None);
+
+        let blocked = {
+            let mgr = mgr.clone();
+
+            thread::spawn(move || mgr.publish(topic(0), &b"data: a".to_vec()).unwrap())
+        };
+
+        entered_rx.recv().unwrap();
+
+        // Topics spread over the shards, so most are not on the blocked one
+        let (done_tx, done_rx) = channel();
+
+        for index in 1..TOPICS {
+            let mgr = mgr.clone();
+            let done = done_tx.clone();
+
+            thread::spawn(move || {
+                mgr.publish(topic(index), &b"data: b".to_vec()).unwrap();
+                done.send(index).unwrap_or(());
+            });
+        }
+
+        let published = done_rx.recv_timeout(Duration::from_secs(5));
+
+        release_tx.send(()).unwrap();
+        blocked.join().unwrap();
+
+        assert!(published.is_ok());
+    }
+}
@@ -3,6 +3,7 @@ extern crate esper;
 #[cfg(test)]
 mod tests {
     use esper::Message;
+    use esper::message::Event;
 
     #[test]
     fn new_message_appends_delimiter() {
@@ -11,4 +12,119 @@ mod tests {
 
         assert_eq!(expected.as_slice(), msg.as_slice());
     }
+
+    #[test]
+    fn json_event_serializes_all_fields() {
+        let event = Event::from_json(br#"{"event": "update", "data": "hello", "id": "7", "retry": 5000}"#).unwrap();
+
+        assert_eq!(b"event: update\nid: 7\nretry: 5000\ndata: hello".to_vec(), event.to_frame());
+    }
+
+    #[test]
+    fn json_event_splits_multi_line_data() {
+        let event = Event::from_json(br#"{"data": "one\ntwo\r\nthree"}"#).unwrap();
+
+        assert_eq!(b"data: one\ndata: two\ndata: three\n\n".to_vec().as_slice(), event.to_message().as_slice());
+    }
+
+    #[test]
+    fn json_event_encodes_non_string_data() {
+        let event = Event::from_json(br#"{"data": {"some": "data"}}"#).unwrap();
+
+        assert_eq!(br#"data: {"some":"data"}"#.to_vec(), event.to_frame());
+    }
+
+    #[test]
+    fn json_event_requires_data() {
+        assert!(Event::from_json(br#"{"event": "update"}"#).is_err());
+    }
+
+    #[test]
+    fn json_event_rejects_line_breaks_in_fields() {
+        assert!(Event::from_json(br#"{"event": "a\ndata: injected", "data": "x"}"#).is_err());
+        assert!(Event::from_json(br#"{"id": "1\r2", "data": "x"}"#).is_err());
+    }
+
+    #[test]
+    fn json_event_rejects_invalid_json() {
+        assert!(Event::from_json(b"data: hello").is_err());
+        assert!(Event::from_json(b"[1, 2]").is_err());
+    }
+
+    #[test]
+    fn parse_normalizes_line_endings_and_whitespace() {
+        let event = Event::parse(b"event:update\r\ndata: one\rdata:two\n\n").unwrap();
+
+        assert_eq!(b"event: update\ndata: one\ndata: two".to_vec(), event.to_frame());
+    }
+
+    #[test]
+    fn parse_drops_comments() {
+        let event = Event::parse(b": keep alive\ndata: hello").unwrap();
+
+        assert_eq!(b"data: hello".to_vec(), event.to_frame());
+    }
+
+    #[test]
+    fn parse_keeps_last_id_and_event() {
+        let event = Event::parse(b"id: 1\nevent: a\nid: 2\nevent: b\ndata: x").unwrap();
+
+        assert_eq!(Some("2".to_owned()), event.id);
+        assert_eq!(Some("b".to_owned()), event.event);
+    }
+
+    #[test]
+    fn parse_allows_events_without_data() {
+        let event = Event::parse(b"retry: 1000").unwrap();
+
+        assert_eq!(b"retry: 1000".to_vec(), event.to_frame());
+    }
+
+    #[test]
+    fn parse_rejects_embedded_blank_lines() {
+        assert!(Event::parse(b"data: one\n\ndata: two").is_err());
+        assert!(Event::parse(b"data: one\r\n\r\ndata: two").is_err());
+    }
+
+    #[test]
+    fn parse_rejects_unknown_fields() {
+        assert!(Event::parse(b"data: one\nhello world").is_err());
+        assert!(Event::parse(b"foo: bar").is_err());
+    }
+
+    #[test]
+    fn parse_rejects_invalid_retry() {
+        assert!(Event::parse(b"retry: soon\ndata: x").is_err());
+        assert!(Event::parse(b"retry: +5\ndata: x").is_err());
+    }
+
+    #[test]
+    fn parse_rejects_empty_and_invalid_utf8() {
+        assert!(Event::parse(b"").is_err());
+        assert!(Event::parse(b": only a comment").is_err());
+        assert!(Event::parse(&[100, 97, 116, 97, 58, 255]).is_err());
+    }
+
+    #[test]
+    fn tagged_message_names_unnamed_events_after_topic() {
+        let msg = Message::new(&b"id: 1\ndata: hello".to_vec()).tagged("abcdef123");
+
+        assert_eq!(b"event: abcdef123\nid: 1\ndata: hello\n\n".to_vec().as_slice(), msg.as_slice());
+        assert_eq!(Some("1"), msg.id());
+    }
+
+    #[test]
+    fn tagged_message_prefixes_named_events() {
+        let msg = Message::new(&b"event: update\ndata: hello".to_vec()).tagged("abcdef123");
+
+        assert_eq!(b"event: abcdef123:update\ndata: hello\n\n".to_vec().as_slice(), msg.as_slice());
+    }
+
+    #[test]
+    fn cloned_messages_share_their_body() {
+        let msg = Message::new(&vec![65 as u8; 2048]);
+        let copy = msg.clone();
+
+        assert_eq!(msg.as_slice().as_ptr(), copy.as_slice().as_ptr());
+    }
 }
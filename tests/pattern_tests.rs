@@ -0,0 +1,82 @@
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use esper::{Pattern, PatternTrie, TopicRules};
+
+    fn pattern(id: &str) -> Pattern {
+        Pattern::parse(id, &TopicRules::default()).unwrap()
+    }
+
+    #[test]
+    fn valid_prefix_pattern() {
+        let pattern = pattern("org123*");
+
+        assert_eq!("org123", pattern.prefix());
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!(Pattern::parse("org12345", &TopicRules::default()).is_err());
+        assert!(Pattern::parse("org/123*", &TopicRules::default()).is_err());
+        assert!(Pattern::parse("", &TopicRules::default()).is_err());
+    }
+
+    #[test]
+    fn prefix_pattern_matches() {
+        let pattern = pattern("org123*");
+
+        assert!(pattern.matches("org123"));
+        assert!(pattern.matches("org123alerts"));
+        assert!(!pattern.matches("org12"));
+        assert!(!pattern.matches("xorg123alerts"));
+    }
+
+    #[test]
+    fn glob_pattern_matches() {
+        let pattern = pattern("org*alerts");
+
+        assert!(pattern.matches("org123alerts"));
+        assert!(pattern.matches("orgalerts"));
+        assert!(pattern.matches("orgalertsalerts"));
+        assert!(!pattern.matches("org123alertsx"));
+
+        assert!(self::pattern("*").matches("anything"));
+        assert!(self::pattern("a*b*c").matches("aXXbYYc"));
+        assert!(!self::pattern("a*b*c").matches("aXXcYYb"));
+    }
+
+    #[test]
+    fn trie_finds_matching_patterns() {
+        let mut trie = PatternTrie::new();
+
+        trie.insert(pattern("org*"));
+        trie.insert(pattern("org123*"));
+        trie.insert(pattern("org*alerts"));
+        trie.insert(pattern("other*"));
+
+        let mut found: Vec<&str> = trie.matches("org123alerts").iter().map(|p| p.id()).collect();
+        found.sort();
+
+        assert_eq!(vec!["org*", "org*alerts", "org123*"], found);
+        assert_eq!(0, trie.matches("abcdef123").len());
+    }
+
+    #[test]
+    fn trie_removes_patterns() {
+        let mut trie = PatternTrie::new();
+
+        trie.insert(pattern("org*"));
+        trie.insert(pattern("org123*"));
+        trie.insert(pattern("org123*"));
+        assert_eq!(2, trie.len());
+
+        trie.remove(&pattern("org123*"));
+        assert_eq!(1, trie.len());
+        assert_eq!(1, trie.matches("org123alerts").len());
+
+        trie.remove(&pattern("org*"));
+        assert!(trie.is_empty());
+        assert_eq!(0, trie.matches("org123alerts").len());
+    }
+}
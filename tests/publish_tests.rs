@@ -0,0 +1,146 @@
+extern crate hyper;
+extern crate esper;
+
+// Subscribers receive the same bytes as are retained for replay, so these
+// tests follow a publish body through the Manager and assert on the exact
+// bytes of the replayed messages, or of those queued for a subscriber when
+// the body is read and published as the handler does.
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use hyper::{Next, StatusCode};
+
+    use esper::{BinaryPolicy, Client, Manager, Notify, Topic};
+    use esper::handler::{publish_body, read_body, Body, Format};
+    use esper::message::Event;
+
+    struct Ignore;
+
+    impl Notify for Ignore {
+        fn notify(&self, _next: Next) -> bool {
+            true
+        }
+    }
+
+    fn topic() -> Topic {
+        Topic::validate(0, "abcdef123".to_owned()).unwrap()
+    }
+
+    fn received(event: Event) -> Vec<u8> {
+        let mgr = Manager::new();
+
+        mgr.publish(topic(), &event.to_frame()).unwrap();
+
+        mgr.replay(&topic(), "unknown").remove(0).as_slice().to_vec()
+    }
+
+    #[test]
+    fn subscribers_receive_exactly_the_event() {
+        let event = Event::parse(b"event: update\ndata: hello").unwrap();
+
+        assert_eq!(b"event: update\ndata: hello\n\n".to_vec(), received(event));
+    }
+
+    #[test]
+    fn line_endings_and_trailing_breaks_are_normalized() {
+        let event = Event::parse(b"data: a\r\ndata: b\r\n\r\n").unwrap();
+
+        assert_eq!(b"data: a\ndata: b\n\n".to_vec(), received(event));
+    }
+
+    #[test]
+    fn large_bodies_arrive_intact() {
+        let data: String = ::std::iter::repeat('x').take(10000).collect();
+        let body = format!("data: {}", data);
+
+        let bytes = received(Event::parse(body.as_bytes()).unwrap());
+
+        assert_eq!(body.len() + 2, bytes.len());
+        assert_eq!(format!("{}\n\n", body).into_bytes(), bytes);
+        assert!(!bytes.contains(&0));
+    }
+
+    // Reads the body in chunks of the given size, then publishes it to a
+    // subscribed client and returns what the client is sent
+    fn delivered(body: &[u8], chunk: usize, kind: Body, format: Format, binary: BinaryPolicy) -> Vec<u8> {
+        let mgr = Manager::new();
+        let client = Client::new();
+        let mut chunks = body.chunks(chunk);
+
+        mgr.subscribe(client.clone(), topic(), Ignore, None);
+
+        let read = |buf: &mut [u8]| -> io::Result<usize> {
+            match chunks.next() {
+                Some(chunk) => {
+                    buf[..chunk.len()].copy_from_slice(chunk);
+
+                    Ok(chunk.len())
+                }
+
+                None => Ok(0)
+            }
+        };
+
+        let body = read_body(read, kind, 65536, &mut Vec::new()).unwrap().unwrap();
+
+        publish_body(&mgr, topic(), &body, format, binary).unwrap();
+
+        mgr.messages_for(client).iter().flat_map(|msg| msg.as_slice().to_vec()).collect()
+    }
+
+    #[test]
+    fn subscribers_receive_exactly_a_body_with_a_length() {
+        let body = b"event: update\ndata: hello";
+        let bytes = delivered(body, 4096, Body::Len(body.len() as u64), Format::Text, BinaryPolicy::Reject);
+
+        assert_eq!(b"event: update\ndata: hello\n\n".to_vec(), bytes);
+    }
+
+    #[test]
+    fn subscribers_receive_exactly_a_chunked_body() {
+        let data: String = ::std::iter::repeat('x').take(10000).collect();
+        let body = format!("data: {}", data);
+
+        let bytes = delivered(body.as_bytes(), 1000, Body::Chunked, Format::Text, BinaryPolicy::Reject);
+
+        assert_eq!(format!("{}\n\n", body).into_bytes(), bytes);
+        assert!(!bytes.contains(&0));
+    }
+
+    #[test]
+    fn subscribers_receive_binary_bodies_base64_encoded() {
+        let body = b"\x00\xff\xfe";
+        let bytes = delivered(body, 4096, Body::Len(3), Format::Text, BinaryPolicy::Base64);
+
+        assert_eq!(b"data: AP/+\n\n".to_vec(), bytes);
+    }
+
+    #[test]
+    fn non_utf8_bodies_are_a_bad_request() {
+        let mgr = Manager::new();
+
+        for &format in [Format::Text, Format::Json].iter() {
+            let err = publish_body(&mgr, topic(), b"data: \xff\xfe", format, BinaryPolicy::Reject).unwrap_err();
+
+            assert_eq!(StatusCode::BadRequest, err.status);
+            assert_eq!("invalid_message", err.error);
+        }
+
+        assert!(mgr.replay(&topic(), "unknown").is_empty());
+    }
+
+    #[test]
+    fn binary_bodies_can_be_base64_encoded() {
+        let event = Event::from_binary(b"\x00\xff\xfe");
+
+        assert_eq!(b"data: AP/+\n\n".to_vec(), received(event));
+    }
+
+    #[test]
+    fn binary_policy_from_str() {
+        assert_eq!(Ok(BinaryPolicy::Reject), "reject".parse());
+        assert_eq!(Ok(BinaryPolicy::Base64), "base64".parse());
+        assert!("hex".parse::<BinaryPolicy>().is_err());
+    }
+}
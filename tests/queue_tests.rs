@@ -0,0 +1,263 @@
+extern crate hyper;
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use hyper::Next;
+
+    use esper::{Client, Manager, Message, Notify, Options, OverflowPolicy, Queue, QueueLimits, Topic};
+    use esper::queue::Push;
+
+    struct Ignore;
+
+    impl Notify for Ignore {
+        fn notify(&self, _next: Next) -> bool {
+            true
+        }
+    }
+
+    fn limits(max_messages: usize, max_bytes: usize, policy: OverflowPolicy) -> QueueLimits {
+        QueueLimits {
+            max_messages: max_messages,
+            max_bytes: max_bytes,
+            policy: policy
+        }
+    }
+
+    fn message(body: &str) -> Message {
+        Message::new(&body.as_bytes().to_vec())
+    }
+
+    fn bodies(queue: &mut Queue) -> Vec<String> {
+        queue.take().iter().map(|msg| String::from_utf8_lossy(msg.as_slice()).trim_right().to_owned()).collect()
+    }
+
+    #[test]
+    fn queue_within_limits() {
+        let limits = limits(2, 0, OverflowPolicy::DropOldest);
+        let mut queue = Queue::new();
+
+        assert_eq!(Push::Queued(0), queue.push(message("data: a"), &limits));
+        assert_eq!(Push::Queued(0), queue.push(message("data: b"), &limits));
+        assert_eq!(2, queue.len());
+        assert_eq!(18, queue.bytes());
+
+        assert_eq!(vec!["data: a", "data: b"], bodies(&mut queue));
+        assert!(queue.is_empty());
+        assert_eq!(0, queue.bytes());
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let limits = limits(2, 0, OverflowPolicy::DropOldest);
+        let mut queue = Queue::new();
+
+        queue.push(message("data: a"), &limits);
+        queue.push(message("data: b"), &limits);
+
+        assert_eq!(Push::Queued(1), queue.push(message("data: c"), &limits));
+        assert_eq!(vec!["data: b", "data: c"], bodies(&mut queue));
+    }
+
+    #[test]
+    fn drop_oldest_by_bytes() {
+        let limits = limits(0, 20, OverflowPolicy::DropOldest);
+        let mut queue = Queue::new();
+
+        queue.push(message("data: a"), &limits);
+        queue.push(message("data: b"), &limits);
+
+        assert_eq!(Push::Queued(1), queue.push(message("data: c"), &limits));
+        assert_eq!(vec!["data: b", "data: c"], bodies(&mut queue));
+    }
+
+    #[test]
+    fn drop_newest() {
+        let limits = limits(2, 0, OverflowPolicy::DropNewest);
+        let mut queue = Queue::new();
+
+        queue.push(message("data: a"), &limits);
+        queue.push(message("data: b"), &limits);
+
+        assert_eq!(Push::Dropped, queue.push(message("data: c"), &limits));
+        assert_eq!(vec!["data: a", "data: b"], bodies(&mut queue));
+    }
+
+    #[test]
+    fn disconnect() {
+        let limits = limits(1, 0, OverflowPolicy::Disconnect);
+        let mut queue = Queue::new();
+
+        queue.push(message("data: a"), &limits);
+
+        assert_eq!(Push::Overflow, queue.push(message("data: b"), &limits));
+    }
+
+    #[test]
+    fn coalesce_by_event_type() {
+        let limits = limits(3, 0, OverflowPolicy::Coalesce);
+        let mut queue = Queue::new();
+
+        queue.push(message("event: price\ndata: 1"), &limits);
+        queue.push(message("event: news\ndata: a"), &limits);
+        queue.push(message("event: price\ndata: 2"), &limits);
+
+        assert_eq!(Push::Queued(2), queue.push(message("event: price\ndata: 3"), &limits));
+        assert_eq!(vec!["event: news\ndata: a", "event: price\ndata: 3"], bodies(&mut queue));
+    }
+
+    #[test]
+    fn coalesce_drops_oldest_without_matching_events() {
+        let limits = limits(2, 0, OverflowPolicy::Coalesce);
+        let mut queue = Queue::new();
+
+        queue.push(message("event: a\ndata: 1"), &limits);
+        queue.push(message("event: b\ndata: 2"), &limits);
+
+        assert_eq!(Push::Queued(1), queue.push(message("event: c\ndata: 3"), &limits));
+        assert_eq!(vec!["event: b\ndata: 2", "event: c\ndata: 3"], bodies(&mut queue));
+    }
+
+    #[test]
+    fn messages_larger_than_the_queue_are_dropped() {
+        let limits = limits(0, 8, OverflowPolicy::DropOldest);
+        let mut queue = Queue::new();
+
+        assert_eq!(Push::Dropped, queue.push(message("data: too large"), &limits));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn messages_larger_than_the_queue_disconnect() {
+        let limits = limits(0, 8, OverflowPolicy::Disconnect);
+        let mut queue = Queue::new();
+
+        assert_eq!(Push::Overflow, queue.push(message("data: too large"), &limits));
+    }
+
+    fn publish_oversized(policy: OverflowPolicy) -> Manager {
+        let mgr = Manager::with_options(Options {
+            queue_limits: limits(0, 8, policy),
+            ..Options::default()
+        });
+        let topic = Topic::validate(0, "abcdef123".to_owned()).unwrap();
+
+        mgr.subscribe(Client::new(), topic.clone(), Ignore, None);
+        mgr.publish(topic, &b"data: too large".to_vec()).unwrap();
+
+        mgr
+    }
+
+    #[test]
+    fn oversized_messages_are_counted() {
+        let stats = publish_oversized(OverflowPolicy::DropOldest).stats();
+
+        assert_eq!(1, stats.dropped);
+        assert_eq!(0, stats.disconnected);
+
+        let stats = publish_oversized(OverflowPolicy::Disconnect).stats();
+
+        assert_eq!(1, stats.dropped);
+        assert_eq!(1, stats.disconnected);
+    }
+
+    #[test]
+    fn coalesce_keeps_unnamed_events_apart() {
+        let limits = limits(3, 0, OverflowPolicy::Coalesce);
+        let mut queue = Queue::new();
+
+        queue.push(message("data: 1"), &limits);
+        queue.push(message("data: 2"), &limits);
+        queue.push(message("data: 3"), &limits);
+
+        assert_eq!(Push::Queued(1), queue.push(message("data: 4"), &limits));
+        assert_eq!(vec!["data: 2", "data: 3", "data: 4"], bodies(&mut queue));
+    }
+
+    #[test]
+    fn coalesce_only_replaces_named_events() {
+        let limits = limits(3, 0, OverflowPolicy::Coalesce);
+        let mut queue = Queue::new();
+
+        queue.push(message("data: 1"), &limits);
+        queue.push(message("event: price\ndata: 2"), &limits);
+        queue.push(message("data: 3"), &limits);
+
+        assert_eq!(Push::Queued(1), queue.push(message("event: price\ndata: 4"), &limits));
+        assert_eq!(vec!["data: 1", "data: 3", "event: price\ndata: 4"], bodies(&mut queue));
+    }
+
+    #[test]
+    fn overflow_policy_from_str() {
+        assert_eq!(Ok(OverflowPolicy::DropOldest), "drop-oldest".parse());
+        assert_eq!(Ok(OverflowPolicy::DropNewest), "drop-newest".parse());
+        assert_eq!(Ok(OverflowPolicy::Disconnect), "disconnect".parse());
+        assert_eq!(Ok(OverflowPolicy::Coalesce), "coalesce".parse());
+        assert!("block".parse::<OverflowPolicy>().is_err());
+    }
+
+    fn would_block() -> io::Error {
+        io::Error::new(io::ErrorKind::WouldBlock, "would block")
+    }
+
+    #[test]
+    fn written_messages_leave_the_queue() {
+        let limits = limits(0, 0, OverflowPolicy::DropOldest);
+        let mut queue = Queue::new();
+        let mut written = Vec::new();
+
+        queue.push(message("data: a"), &limits);
+        queue.push(message("data: b"), &limits);
+
+        let rest = queue.write_to(|buf| {
+            written.extend_from_slice(buf);
+
+            Ok(buf.len())
+        });
+
+        assert_eq!(None, rest.unwrap());
+        assert_eq!(b"data: a\n\ndata: b\n\n".to_vec(), written);
+        assert!(queue.is_empty());
+        assert_eq!(0, queue.bytes());
+    }
+
+    #[test]
+    fn partially_written_messages_leave_their_tail() {
+        let limits = limits(0, 0, OverflowPolicy::DropOldest);
+        let mut queue = Queue::new();
+
+        queue.push(message("data: a"), &limits);
+        queue.push(message("data: b"), &limits);
+
+        assert_eq!(Some(b"a\n\n".to_vec()), queue.write_to(|_| Ok(6)).unwrap());
+        assert_eq!(vec!["data: b"], bodies(&mut queue));
+    }
+
+    #[test]
+    fn messages_stay_queued_while_the_writer_blocks() {
+        let limits = limits(2, 0, OverflowPolicy::Disconnect);
+        let mut queue = Queue::new();
+
+        queue.push(message("data: a"), &limits);
+        queue.push(message("data: b"), &limits);
+
+        assert_eq!(Some(Vec::new()), queue.write_to(|_| Err(would_block())).unwrap());
+        assert_eq!(2, queue.len());
+
+        // The limits still apply to what the client could not take
+        assert_eq!(Push::Overflow, queue.push(message("data: c"), &limits));
+    }
+
+    #[test]
+    fn write_errors_are_returned() {
+        let limits = limits(0, 0, OverflowPolicy::DropOldest);
+        let mut queue = Queue::new();
+
+        queue.push(message("data: a"), &limits);
+
+        assert!(queue.write_to(|_| Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))).is_err());
+        assert_eq!(1, queue.len());
+    }
+}
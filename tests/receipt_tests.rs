@@ -0,0 +1,54 @@
+extern crate hyper;
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+
+    use esper::{IdPolicy, Manager, NoSubscribers, Options, Topic};
+
+    fn topic(id: &str) -> Topic {
+        Topic::validate(0, id.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn receipt_without_subscribers() {
+        let mgr = Manager::with_options(Options {
+            id_policy: IdPolicy::Override,
+            ..Options::default()
+        });
+
+        let receipt = mgr.publish(topic("abcdef123"), &b"data: a".to_vec()).unwrap();
+
+        assert_eq!(Some("1".to_owned()), receipt.id);
+        assert_eq!("abcdef123", receipt.topic);
+        assert_eq!(0, receipt.subscribers);
+        assert_eq!(0, receipt.failed);
+    }
+
+    #[test]
+    fn receipt_json() {
+        let mgr = Manager::new();
+        let receipt = mgr.publish(topic("abcdef123"), &b"data: a".to_vec()).unwrap();
+
+        assert_eq!("{\"id\":null,\"topic\":\"abcdef123\",\"subscribers\":0,\"failed\":0}", receipt.to_json().unwrap());
+    }
+
+    #[test]
+    fn no_subscribers_status() {
+        assert_eq!(StatusCode::Accepted, NoSubscribers::Accept.status(false));
+        assert_eq!(StatusCode::NotFound, NoSubscribers::NotFound.status(false));
+        assert_eq!(StatusCode::Gone, NoSubscribers::Gone.status(false));
+
+        assert_eq!(StatusCode::Accepted, NoSubscribers::NotFound.status(true));
+        assert_eq!(StatusCode::Accepted, NoSubscribers::Gone.status(true));
+    }
+
+    #[test]
+    fn no_subscribers_from_str() {
+        assert_eq!(Ok(NoSubscribers::Accept), "accept".parse());
+        assert_eq!(Ok(NoSubscribers::NotFound), "404".parse());
+        assert_eq!(Ok(NoSubscribers::Gone), "410".parse());
+        assert!("500".parse::<NoSubscribers>().is_err());
+    }
+}
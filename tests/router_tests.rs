@@ -0,0 +1,70 @@
+extern crate hyper;
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Get, Post, Delete, StatusCode};
+
+    use esper::auth::AuthError;
+    use esper::router::{route, ApiError, Endpoint, RouteError};
+    use esper::TopicError;
+
+    fn forbidden() -> AuthError {
+        AuthError::ForbiddenTopic {
+            topic: "abcdef456".to_owned(),
+            claim: "sub"
+        }
+    }
+
+    #[test]
+    fn routes_known_endpoints() {
+        assert_eq!(Ok(Endpoint::Stats), route(&Get, "/stats"));
+        assert_eq!(Ok(Endpoint::Subscribe("abcdef123".to_owned())), route(&Get, "/subscribe/abcdef123"));
+        assert_eq!(Ok(Endpoint::Subscribe(String::new())), route(&Get, "/subscribe"));
+        assert_eq!(Ok(Endpoint::Publish("abcdef123".to_owned())), route(&Post, "/publish/abcdef123"));
+        assert_eq!(Ok(Endpoint::PublishBatch("abcdef123".to_owned())), route(&Post, "/publish/abcdef123/batch"));
+        assert_eq!(Ok(Endpoint::FanOut), route(&Post, "/publish"));
+    }
+
+    #[test]
+    fn decodes_topic_ids() {
+        assert_eq!(Ok(Endpoint::Subscribe("abcdef123,abcdef456".to_owned())), route(&Get, "/subscribe/abcdef123%2Cabcdef456"));
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        assert_eq!(Err(RouteError::NotFound), route(&Get, "/"));
+        assert_eq!(Err(RouteError::NotFound), route(&Get, "/subscribeXYZ/abcdef123"));
+        assert_eq!(Err(RouteError::NotFound), route(&Post, "/publishing/abcdef123"));
+        assert_eq!(Err(RouteError::NotFound), route(&Get, "/stats/abcdef123"));
+    }
+
+    #[test]
+    fn wrong_methods_are_not_allowed() {
+        assert_eq!(Err(RouteError::MethodNotAllowed(Post)), route(&Get, "/publish/abcdef123"));
+        assert_eq!(Err(RouteError::MethodNotAllowed(Get)), route(&Post, "/subscribe/abcdef123"));
+        assert_eq!(Err(RouteError::MethodNotAllowed(Get)), route(&Delete, "/stats"));
+    }
+
+    #[test]
+    fn error_statuses() {
+        assert_eq!(StatusCode::NotFound, ApiError::from_route(RouteError::NotFound).status);
+        assert_eq!(StatusCode::BadRequest, ApiError::from_topic(TopicError::TooShort(8)).status);
+        assert_eq!(StatusCode::Unauthorized, ApiError::from_auth(AuthError::MissingToken).status);
+        assert_eq!(StatusCode::Unauthorized, ApiError::from_auth(AuthError::Expired).status);
+        assert_eq!(StatusCode::Forbidden, ApiError::from_auth(forbidden()).status);
+        assert_eq!(StatusCode::PayloadTooLarge, ApiError::too_large(65536).status);
+
+        let err = ApiError::from_route(RouteError::MethodNotAllowed(Get));
+
+        assert_eq!(StatusCode::MethodNotAllowed, err.status);
+        assert_eq!(Some(Get), err.allow);
+    }
+
+    #[test]
+    fn error_body_is_json() {
+        let err = ApiError::from_auth(forbidden());
+
+        assert_eq!("{\"error\":\"forbidden_topic\",\"message\":\"Token does not grant access to topic \\\"abcdef456\\\" in its sub claim\"}", err.to_json().unwrap());
+    }
+}
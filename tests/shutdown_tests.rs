@@ -0,0 +1,133 @@
+extern crate hyper;
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::{Next, StatusCode};
+
+    use esper::{Client, Manager, Message, Notify, Options, OverflowPolicy, Queue, QueueLimits, Topic};
+    use esper::router::ApiError;
+
+    #[derive(Clone)]
+    struct Wakeups(Arc<AtomicUsize>);
+
+    impl Notify for Wakeups {
+        fn notify(&self, _next: Next) -> bool {
+            self.0.fetch_add(1, Ordering::Relaxed);
+
+            true
+        }
+    }
+
+    fn topic(id: &str) -> Topic {
+        Topic::validate(0, id.to_owned()).unwrap()
+    }
+
+    fn goodbye() -> Message {
+        Message::new(&b"event: shutdown\ndata: \n\n".to_vec())
+    }
+
+    #[test]
+    fn subscribers_receive_a_final_message() {
+        let mgr = Manager::new();
+        let client = Client::new();
+        let wakeups = Wakeups(Arc::new(AtomicUsize::new(0)));
+
+        mgr.subscribe(client.clone(), topic("abcdef123"), wakeups.clone(), None);
+        mgr.publish(topic("abcdef123"), &b"data: 1\n\n".to_vec()).unwrap();
+
+        assert!(!mgr.is_closing());
+        assert_eq!(mgr.shutdown(&goodbye()), 1);
+        assert!(mgr.is_closing());
+
+        let messages = mgr.messages_for(client.clone());
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].as_slice(), goodbye().as_slice());
+        assert_eq!(wakeups.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn clients_on_several_topics_receive_one_final_message() {
+        let mgr = Manager::new();
+        let client = Client::new();
+        let wakeups = Wakeups(Arc::new(AtomicUsize::new(0)));
+
+        mgr.subscribe_all(client.clone(), &[topic("abcdef123"), topic("abcdef456")], wakeups.clone());
+
+        assert_eq!(mgr.shutdown(&goodbye()), 1);
+        assert_eq!(mgr.messages_for(client).len(), 1);
+        assert_eq!(wakeups.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn full_queues_still_receive_the_final_message() {
+        let mgr = Manager::with_options(Options {
+            queue_limits: QueueLimits {
+                max_messages: 1,
+                max_bytes: 0,
+                policy: OverflowPolicy::DropNewest
+            },
+            ..Options::default()
+        });
+
+        let client = Client::new();
+
+        mgr.subscribe(client.clone(), topic("abcdef123"), Wakeups(Arc::new(AtomicUsize::new(0))), None);
+        mgr.publish(topic("abcdef123"), &b"data: 1\n\n".to_vec()).unwrap();
+        mgr.shutdown(&goodbye());
+
+        assert_eq!(mgr.messages_for(client).len(), 2);
+    }
+
+    #[test]
+    fn clients_are_finished_once_the_final_message_is_taken() {
+        let mgr = Manager::new();
+        let client = Client::new();
+
+        mgr.subscribe(client.clone(), topic("abcdef123"), Wakeups(Arc::new(AtomicUsize::new(0))), None);
+
+        assert!(!mgr.is_finished(client.clone()));
+
+        mgr.shutdown(&goodbye());
+
+        assert!(!mgr.is_finished(client.clone()));
+
+        mgr.messages_for(client.clone());
+
+        assert!(mgr.is_finished(client));
+    }
+
+    #[test]
+    fn nothing_is_queued_after_the_final_message() {
+        let mgr = Manager::new();
+        let client = Client::new();
+
+        mgr.subscribe(client.clone(), topic("abcdef123"), Wakeups(Arc::new(AtomicUsize::new(0))), None);
+        mgr.shutdown(&goodbye());
+        mgr.publish(topic("abcdef123"), &b"data: 1\n\n".to_vec()).unwrap();
+
+        assert_eq!(mgr.messages_for(client).len(), 1);
+    }
+
+    #[test]
+    fn final_message_is_only_queued_once() {
+        let mut queue = Queue::new();
+
+        assert!(queue.push_final(&goodbye()));
+        assert!(!queue.push_final(&goodbye()));
+        assert!(queue.is_closed());
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn requests_are_refused_while_shutting_down() {
+        let err = ApiError::shutting_down();
+
+        assert_eq!(err.status, StatusCode::ServiceUnavailable);
+        assert_eq!(err.error, "shutting_down");
+    }
+}
@@ -0,0 +1,230 @@
+extern crate hyper;
+extern crate esper;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use hyper::Next;
+
+    use esper::{Client, Manager, Notify, Pattern, Topic, TopicRules};
+
+    #[derive(Clone)]
+    struct Ignore;
+
+    impl Notify for Ignore {
+        fn notify(&self, _next: Next) -> bool {
+            true
+        }
+    }
+
+    const TOPICS: [&'static str; 5] = ["alpha0001", "alpha0002", "beta00001", "beta00002", "gamma0001"];
+    const PATTERNS: [&'static str; 3] = ["alpha*", "beta*", "*0001"];
+
+    fn topic(index: usize) -> Topic {
+        Topic::validate(0, TOPICS[index].to_owned()).unwrap()
+    }
+
+    fn pattern(index: usize) -> Pattern {
+        Pattern::parse(PATTERNS[index], &TopicRules::default()).unwrap()
+    }
+
+    fn pattern_matches(pattern: usize, topic: usize) -> bool {
+        match pattern {
+            0 => TOPICS[topic].starts_with("alpha"),
+            1 => TOPICS[topic].starts_with("beta"),
+            _ => TOPICS[topic].ends_with("0001")
+        }
+    }
+
+    // xorshift; random enough to shuffle operations and reproducible by seed
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    // What each client is expected to be subscribed to
+    #[derive(Default)]
+    struct Model {
+        topics: HashMap<usize, Vec<usize>>,
+        patterns: HashMap<usize, usize>
+    }
+
+    impl Model {
+        fn remove(&mut self, client: usize) -> () {
+            self.topics.remove(&client);
+            self.patterns.remove(&client);
+        }
+
+        fn clients(&self) -> usize {
+            self.topics.len() + self.patterns.len()
+        }
+
+        fn topic_count(&self) -> usize {
+            self.topics.values().flat_map(|topics| topics.iter()).collect::<HashSet<_>>().len()
+        }
+
+        fn pattern_count(&self) -> usize {
+            self.patterns.values().collect::<HashSet<_>>().len()
+        }
+
+        fn subscribers(&self, topic: usize) -> usize {
+            self.topics.values().filter(|topics| topics.contains(&topic)).count() +
+                self.patterns.values().filter(|pattern| pattern_matches(**pattern, topic)).count()
+        }
+    }
+
+    fn run(seed: u64, steps: usize) -> () {
+        let mut rng = Rng(seed);
+        let mut model = Model::default();
+
+        let mgr = Manager::new();
+        let clients: Vec<Client> = (0..8).map(|_| Client::new()).collect();
+
+        for step in 0..steps {
+            let c = rng.below(clients.len());
+            let client = clients[c].clone();
+
+            match rng.below(8) {
+                0 => {
+                    let t = rng.below(TOPICS.len());
+
+                    mgr.subscribe(client, topic(t), Ignore, None);
+
+                    model.remove(c);
+                    model.topics.insert(c, vec![t]);
+                }
+
+                1 => {
+                    let picked: Vec<usize> = (0..1 + rng.below(3)).map(|_| rng.below(TOPICS.len())).collect();
+                    let topics: Vec<Topic> = picked.iter().map(|t| topic(*t)).collect();
+
+                    mgr.subscribe_all(client, &topics, Ignore);
+
+                    let mut unique = Vec::new();
+
+                    for t in picked {
+                        if !unique.contains(&t) {
+                            unique.push(t);
+                        }
+                    }
+
+                    model.remove(c);
+                    model.topics.insert(c, unique);
+                }
+
+                2 => {
+                    let p = rng.below(PATTERNS.len());
+
+                    mgr.subscribe_pattern(client, pattern(p), Ignore);
+
+                    model.remove(c);
+                    model.patterns.insert(c, p);
+                }
+
+                3 | 4 => {
+                    let t = rng.below(TOPICS.len());
+
+                    mgr.unsubscribe(client, topic(t));
+
+                    let is_empty = match model.topics.get_mut(&c) {
+                        Some(topics) => {
+                            topics.retain(|subscribed| *subscribed != t);
+
+                            topics.is_empty()
+                        }
+
+                        None => false
+                    };
+
+                    if is_empty {
+                        model.topics.remove(&c);
+                    }
+                }
+
+                5 => {
+                    let p = rng.below(PATTERNS.len());
+
+                    mgr.unsubscribe_pattern(client, pattern(p));
+
+                    if model.patterns.get(&c) == Some(&p) {
+                        model.patterns.remove(&c);
+                    }
+                }
+
+                6 => {
+                    mgr.unsubscribe_client(client);
+
+                    model.remove(c);
+                }
+
+                _ => {
+                    let t = rng.below(TOPICS.len());
+                    let receipt = mgr.publish(topic(t), &b"data: a".to_vec()).unwrap();
+
+                    assert_eq!(model.subscribers(t), receipt.subscribers, "seed {} step {}: subscribers of {}", seed, step, TOPICS[t]);
+                }
+            }
+
+            let stats = mgr.stats();
+
+            assert_eq!(model.clients(), stats.clients, "seed {} step {}: clients", seed, step);
+            assert_eq!(model.topic_count(), stats.topics, "seed {} step {}: topics", seed, step);
+            assert_eq!(model.pattern_count(), stats.patterns, "seed {} step {}: patterns", seed, step);
+        }
+
+        // Once everyone is gone nothing may be left behind
+        for client in clients {
+            mgr.unsubscribe_client(client);
+        }
+
+        let stats = mgr.stats();
+
+        assert_eq!((0, 0, 0), (stats.clients, stats.topics, stats.patterns));
+    }
+
+    #[test]
+    fn random_subscriptions_match_stats() {
+        for seed in 1..200 {
+            run(seed * 7919, 300);
+        }
+    }
+
+    #[test]
+    fn unsubscribe_removes_clients_in_any_order() {
+        let mgr = Manager::new();
+        let clients: Vec<Client> = (0..5).map(|_| Client::new()).collect();
+
+        for client in clients.iter() {
+            mgr.subscribe(client.clone(), topic(0), Ignore, None);
+        }
+
+        for (index, client) in clients.iter().enumerate().rev() {
+            mgr.unsubscribe(client.clone(), topic(0));
+
+            assert_eq!(index, mgr.publish(topic(0), &b"data: a".to_vec()).unwrap().subscribers);
+        }
+
+        assert_eq!(0, mgr.stats().topics);
+    }
+
+    #[test]
+    fn empty_topics_keep_their_history() {
+        let mgr = Manager::new();
+        let client = Client::new();
+
+        mgr.subscribe(client.clone(), topic(0), Ignore, None);
+        mgr.publish(topic(0), &b"id: 1\ndata: a".to_vec()).unwrap();
+        mgr.unsubscribe_client(client.clone());
+
+        assert_eq!(0, mgr.stats().topics);
+        assert_eq!(1, mgr.subscribe(client, topic(0), Ignore, Some("0".to_owned())));
+    }
+}
@@ -2,7 +2,7 @@ extern crate esper;
 
 #[cfg(test)]
 mod tests {
-    use esper::Topic;
+    use esper::{Topic, TopicError, TopicRules};
 
     #[test]
     fn valid_alphanumeric_topic_id() {
@@ -38,4 +38,70 @@ mod tests {
 
         assert!(topic_opt.is_some());
     }
+
+    #[test]
+    fn topic_ids_are_lowercased_by_default() {
+        let topic = Topic::parse("ABCDEF123", &TopicRules::default()).unwrap();
+
+        assert_eq!("abcdef123", topic.id());
+    }
+
+    #[test]
+    fn rules_explain_rejections() {
+        let rules = TopicRules::default();
+
+        assert_eq!(Err(TopicError::Missing), Topic::parse("", &rules));
+        assert_eq!(Err(TopicError::TooShort(8)), Topic::parse("abc", &rules));
+        assert_eq!(Err(TopicError::TooLong(64)), Topic::parse(&"a".repeat(65), &rules));
+        assert_eq!(Err(TopicError::InvalidCharacter(':')), Topic::parse("user:4242", &rules));
+    }
+
+    #[test]
+    fn rules_allow_extra_characters() {
+        let rules = TopicRules {
+            extra_chars: ":_/-".to_owned(),
+            min_len: 1,
+            ..TopicRules::default()
+        };
+
+        assert!(Topic::parse("user:42", &rules).is_ok());
+        assert!(Topic::parse("org_7/alerts", &rules).is_ok());
+        assert!(Topic::parse("0b6f3a0e-6f1d-4a3e-9d4c-2b1f0c5e8a7d", &rules).is_ok());
+        assert_eq!(Err(TopicError::InvalidCharacter('.')), Topic::parse("user.42", &rules));
+    }
+
+    #[test]
+    fn rules_can_keep_case() {
+        let rules = TopicRules {
+            case_sensitive: true,
+            ..TopicRules::default()
+        };
+
+        assert_eq!("ABCdef123", Topic::parse("ABCdef123", &rules).unwrap().id());
+    }
+
+    #[test]
+    fn rules_can_require_a_regex() {
+        let rules = TopicRules {
+            extra_chars: ":".to_owned(),
+            min_len: 1,
+            regex: Some(TopicRules::full_match("user:[0-9]+").unwrap()),
+            ..TopicRules::default()
+        };
+
+        assert!(Topic::parse("user:42", &rules).is_ok());
+        assert_eq!(Err(TopicError::Mismatch("^(?:user:[0-9]+)$".to_owned())), Topic::parse("user:42x", &rules));
+        assert!(Topic::parse("xuser:42", &rules).is_err());
+    }
+
+    #[test]
+    fn regex_is_matched_before_lowercasing() {
+        let rules = TopicRules {
+            regex: Some(TopicRules::full_match("[A-Z]{3}[a-z0-9]+").unwrap()),
+            ..TopicRules::default()
+        };
+
+        assert_eq!("abcdef123", Topic::parse("ABCdef123", &rules).unwrap().id());
+        assert!(Topic::parse("abcdef123", &rules).is_err());
+    }
 }